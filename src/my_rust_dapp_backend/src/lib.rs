@@ -4,14 +4,17 @@
 use candid::{CandidType, Principal, Nat};
 use ic_cdk::api::time;
 use ic_cdk_macros::*;
-use ic_cdk_timers::set_timer_interval;
+use ic_cdk_timers::{clear_timer, set_timer, set_timer_interval, TimerId};
 use ic_cdk::storage;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_certification::{AsHashTree, RbTree};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
 
 // ICRC-1 related types for ckBTC integration
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
 pub struct Account {
     owner: Principal,
     subaccount: Option<Vec<u8>>,
@@ -58,16 +61,63 @@ pub enum TransferResult {
 pub struct Transaction {
     amount: u64,
     timestamp: u64,
-    transaction_type: String,
+    kind: TransactionKind,
     tx_hash: Option<String>, // For ckBTC transactions
     ckbtc_address: Option<String>, // ckBTC address used
 }
 
+// 交易类型及其关联数据
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum TransactionKind {
+    Bet { round_id: Option<u64>, tickets: u64 }, // 迁移自旧版本且无法确定轮次的下注没有 round_id
+    Win { round_id: u64, rank: u32 },
+    JackpotWin { round_id: u64 },
+    Refund { round_id: u64 },
+    CkBtcDeposit { block_index: Option<u64> },
+    Withdraw { block_index: u64 },
+    BalanceRecorded,
+    MainAccountRecorded,
+    BalanceUpdate,
+    FakeRecharge,
+    Legacy { transaction_type: String }, // 无法迁移为结构化类型的旧记录
+}
+
+impl TransactionKind {
+    // 类型名称，用于日志和按类型筛选
+    fn name(&self) -> &str {
+        match self {
+            TransactionKind::Bet { .. } => "Bet",
+            TransactionKind::Win { .. } => "Win",
+            TransactionKind::JackpotWin { .. } => "JackpotWin",
+            TransactionKind::Refund { .. } => "Refund",
+            TransactionKind::CkBtcDeposit { .. } => "CkBtcDeposit",
+            TransactionKind::Withdraw { .. } => "Withdraw",
+            TransactionKind::BalanceRecorded => "BalanceRecorded",
+            TransactionKind::MainAccountRecorded => "MainAccountRecorded",
+            TransactionKind::BalanceUpdate => "BalanceUpdate",
+            TransactionKind::FakeRecharge => "FakeRecharge",
+            TransactionKind::Legacy { transaction_type } => transaction_type,
+        }
+    }
+}
+
+// 旧版本使用字符串类型的交易记录，仅用于升级迁移
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct LegacyTransaction {
+    amount: u64,
+    timestamp: u64,
+    transaction_type: String,
+    tx_hash: Option<String>,
+    ckbtc_address: Option<String>,
+    quantity: Option<u64>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Winning {
     amount: u64,
     timestamp: u64,
     round_id: u64,
+    rank: Option<u32>, // 中奖档位（1 为头奖，0 为累积奖金）；升级前的旧记录没有该字段
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -76,23 +126,79 @@ pub struct HistoricalWinner {
     amount: u64,
     timestamp: u64,
     round_id: u64,
+    rank: Option<u32>, // 中奖档位（1 为头奖，0 为累积奖金）；升级前的旧记录没有该字段
+    is_bot: Option<bool>, // 是否为平台流动性机器人
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct User {
     balance: u64,
-    transaction_history: Vec<Transaction>,
+    // 交易明细保存在全局交易日志 TRANSACTION_LOG 中，这里只保留汇总
+    transaction_count: u64, // 也是下一条交易的序号
+    total_deposited: u64,
+    total_wagered: u64,
+    total_won: u64,
+    total_withdrawn: u64,
     winning_history: Vec<Winning>,
     deposit_account: Account, // User's unique deposit account
     principal_text: String, // Store as text for Candid compatibility
     last_balance_check: u64, // Last time balance was checked
+    daily_stake: Option<DailyStake>, // 当日累计下注额，用于每日限额
+    responsible_gambling: Option<ResponsibleGambling>, // 用户自设的理性博彩限制
+    deposit_watermarks: Vec<DepositWatermark>, // 链上账户的入账高水位
+}
+
+// 单个链上账户的入账高水位：新流入 = 链上余额 + outflows - credited
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DepositWatermark {
+    account: Account,
+    credited: u64, // 已入账（或首次观察时作为基线）的流入总额
+    outflows: u64, // 本 canister 从该账户转出的总额（含手续费）
+    last_synced: u64,
+}
+
+// 旧版本的用户结构（交易历史内嵌在用户记录中），仅用于升级迁移
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct LegacyUser {
+    balance: u64,
+    transaction_history: Vec<LegacyTransaction>,
+    winning_history: Vec<Winning>,
+    deposit_account: Account,
+    principal_text: String,
+    last_balance_check: u64,
+    daily_stake: Option<DailyStake>,
+    responsible_gambling: Option<ResponsibleGambling>,
+}
+
+// 每个用户在交易日志中的一页记录
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct TransactionEntry {
+    seq: u64,
+    transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct TransactionPage {
+    entries: Vec<TransactionEntry>,
+    total: u64, // 符合筛选条件的交易总数
+}
+
+// 用户某一天（UTC）的累计下注额
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DailyStake {
+    day: u64, // time() / NANOS_PER_DAY
+    amount: u64,
 }
 
 impl Default for User {
     fn default() -> Self {
         Self {
             balance: 0,
-            transaction_history: vec![],
+            transaction_count: 0,
+            total_deposited: 0,
+            total_wagered: 0,
+            total_won: 0,
+            total_withdrawn: 0,
             winning_history: vec![],
             deposit_account: Account {
                 owner: Principal::anonymous(),
@@ -100,18 +206,135 @@ impl Default for User {
             },
             principal_text: String::new(),
             last_balance_check: 0,
+            daily_stake: None,
+            responsible_gambling: None,
+            deposit_watermarks: vec![],
         }
     }
 }
 
+// 新增：用户的充值账户：本 canister 名下按 principal 派生的 32 字节子账户（首字节为 principal 长度），
+// 只有 canister 能从中转出，因此链上余额才能记为用户余额
+fn deposit_account_for(principal: Principal) -> Account {
+    let bytes = principal.as_slice();
+    let mut subaccount = vec![0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    Account { owner: ic_cdk::id(), subaccount: Some(subaccount) }
+}
+
+// 新增：新建用户；充值账户在创建时即建立零起点的高水位，之后的所有流入都会入账
+fn new_user(principal: Principal) -> User {
+    let deposit_account = deposit_account_for(principal);
+    User {
+        deposit_watermarks: vec![DepositWatermark {
+            account: deposit_account.clone(),
+            credited: 0,
+            outflows: 0,
+            last_synced: time(),
+        }],
+        deposit_account,
+        principal_text: principal.to_string(),
+        last_balance_check: time(),
+        ..Default::default()
+    }
+}
+
+// 按自然窗口（滚动 24 小时 / 7 天 / 30 天）的金额上限，None 表示不限制
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PeriodLimits {
+    daily: Option<u64>,
+    weekly: Option<u64>,
+    monthly: Option<u64>,
+}
+
+// 用户自设的限额
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct GamblingLimits {
+    deposit: PeriodLimits,
+    loss: PeriodLimits, // 净亏损 = 下注 - 中奖/退款
+    session_duration_secs: Option<u64>, // 连续游戏时长上限
+    session_cooldown_secs: u64, // 达到时长上限后的强制休息时间；间隔超过该时间的下注开始新会话
+}
+
+// 用户的理性博彩状态
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ResponsibleGambling {
+    limits: GamblingLimits,
+    pending_limits: Option<GamblingLimits>, // 放宽的限额需等待 LIMIT_INCREASE_DELAY 后生效
+    pending_effective_at: u64,
+    self_excluded_until: Option<u64>, // 自我排除截止时间（纳秒），u64::MAX 表示永久
+    session_started_at: u64,
+    last_bet_at: u64,
+}
+
+// 轮次参与者及其彩票数量；is_bot 标记由平台金库出资的流动性机器人
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Participant {
+    principal: Principal,
+    is_bot: bool,
+    ticket_count: u64,
+    win_probability: f64, // 抽中头奖的概率，查询时按彩票占比计算
+}
+
+// 彩票账本中的一段连续编号：每次购买追加一段，end 为累计到该段末尾的彩票总数（不含）
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TicketRange {
+    principal: Principal,
+    is_bot: bool,
+    end: u64,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Round {
     id: u64,
-    participants: Vec<Principal>,
+    participants: Vec<Participant>, // 按 (principal, is_bot) 排序，每个参与者一条
+    ticket_ranges: Vec<TicketRange>, // 按 end 递增，开奖时二分查找中奖彩票的持有者
+    total_tickets: u64,
     prize_pool: u64,
     start_time: u64,
     end_time: u64,
     winners: Vec<Principal>,
+    jackpot: Option<u64>, // 当前累积奖金，查询时由 JACKPOT 填充
+    upcoming_draws: Option<Vec<u64>>, // 本轮及之后几轮的开奖时间，查询时按开奖时间表填充
+    status: Option<RoundStatus>, // 旧版本保存的轮次没有该字段，视为 Open
+}
+
+// 轮次状态机：Open -> Closing -> Drawing -> Settled；参与人数不足且配置为延长时 Closing -> Open，
+// 获取随机数或结算失败时 Drawing -> Closing 等待重试
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundStatus {
+    Open, // 接受下注
+    Closing, // 已到结束时间，不再接受下注，等待开奖
+    Drawing, // 正在抽取中奖者和派奖
+    Settled, // 已结算并归档
+}
+
+impl RoundStatus {
+    fn can_transition_to(self, to: RoundStatus) -> bool {
+        matches!((self, to),
+            (RoundStatus::Open, RoundStatus::Closing)
+            | (RoundStatus::Closing, RoundStatus::Open)
+            | (RoundStatus::Closing, RoundStatus::Drawing)
+            | (RoundStatus::Drawing, RoundStatus::Closing)
+            | (RoundStatus::Drawing, RoundStatus::Settled))
+    }
+}
+
+impl Round {
+    fn status(&self) -> RoundStatus {
+        self.status.unwrap_or(RoundStatus::Open)
+    }
+    
+    // 按状态机推进，非法转换直接回滚本次调用
+    fn transition(&mut self, to: RoundStatus) {
+        let from = self.status();
+        if !from.can_transition_to(to) {
+            ic_cdk::trap(&format!("Invalid status transition for round {}: {:?} -> {:?}", self.id, from, to));
+        }
+        ic_cdk::println!("🔁 [ROUND_STATUS] Round {}: {:?} -> {:?}", self.id, from, to);
+        self.status = Some(to);
+    }
 }
 
 impl Default for Round {
@@ -119,166 +342,594 @@ impl Default for Round {
         Self {
             id: 0,
             participants: vec![],
+            ticket_ranges: vec![],
+            total_tickets: 0,
             prize_pool: 0,
             start_time: time(),
             end_time: time() + ROUND_DURATION,
             winners: vec![],
+            jackpot: None,
+            upcoming_draws: None,
+            status: Some(RoundStatus::Open),
         }
     }
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
 pub struct SystemStats {
     total_rounds: u64,
     total_bets: u64,
     total_winnings: u64,
     active_users: u64,
     total_ckbtc_deposits: u64,
+    total_fees_collected: Option<u64>, // 查询时由 FEE_ACCOUNT 填充
+    fees_accrued: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CkBtcDeposit {
+    principal: String, // Store as text for Candid compatibility
+    amount: u64,
+    tx_hash: String,
+    timestamp: u64,
+    status: String, // "pending", "confirmed", "failed"
+}
+
+// 奖金分配方式：按档位比例（基点，合计 10000）或 N 个中奖者平分
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum PrizeDistribution {
+    Tiered(Vec<u64>),
+    Equal(u32),
+}
+
+// 参与人数不足时的处理方式：顺延本轮或取消并退款
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ThresholdAction {
+    Extend,
+    Refund,
+}
+
+// 下注被拒绝的原因
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum BetError {
+    InvalidTicketCount,
+    UserNotFound,
+    InsufficientBalance { balance: u64, required: u64 },
+    UserRoundLimitExceeded { limit: u64, held: u64 },
+    RoundFull { limit: u64, remaining: u64 },
+    DailyStakeLimitExceeded { limit: u64, staked_today: u64 },
+    SelfExcluded { until: u64 },
+    SessionCooldown { until: u64 },
+    LossLimitExceeded { limit: u64, lost: u64 },
+    BettingPaused,
+    RoundClosed { round_id: u64 },
+}
+
+impl std::fmt::Display for BetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BetError::InvalidTicketCount => write!(f, "Invalid ticket count"),
+            BetError::UserNotFound => write!(f, "User not found"),
+            BetError::InsufficientBalance { balance, required } => 
+                write!(f, "Insufficient balance for bet. User has {} but needs {}", balance, required),
+            BetError::UserRoundLimitExceeded { limit, held } => 
+                write!(f, "Ticket limit per round is {} and user already holds {}", limit, held),
+            BetError::RoundFull { limit, remaining } => 
+                write!(f, "Round is limited to {} tickets and only {} remain", limit, remaining),
+            BetError::DailyStakeLimitExceeded { limit, staked_today } => 
+                write!(f, "Daily stake limit is {} e8s and user already staked {} e8s today", limit, staked_today),
+            BetError::SelfExcluded { until } if *until == u64::MAX => write!(f, "User is permanently self-excluded"),
+            BetError::SelfExcluded { until } => write!(f, "User is self-excluded until {}", until),
+            BetError::SessionCooldown { until } => write!(f, "Session limit reached, betting resumes at {}", until),
+            BetError::BettingPaused => write!(f, "Betting is paused until the platform is solvent again"),
+            BetError::RoundClosed { round_id } => write!(f, "Round {} is closed for betting", round_id),
+            BetError::LossLimitExceeded { limit, lost } => 
+                write!(f, "Loss limit is {} e8s and user has already lost {} e8s in this period", limit, lost),
+        }
+    }
+}
+
+// 奖池配置，由管理员调整
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct LotteryConfig {
+    prize_distribution: PrizeDistribution,
+    house_fee_bps: u64, // 开奖时从奖池抽取的平台费（基点）
+    jackpot_contribution_bps: u64, // 每轮注入累积奖金的比例（基点）
+    jackpot_odds: u64, // 累积奖金每轮开出的概率为 1/N，0 表示关闭累积奖金
+    min_participants: u32, // 开奖所需的最少真实参与人数（按用户去重，不含机器人）
+    below_threshold_action: ThresholdAction,
+    // 下注上限，0 表示不限制
+    max_tickets_per_user_per_round: u64,
+    max_tickets_per_round: u64, // 达到后本轮提前截止并开奖
+    max_daily_stake_per_user: u64, // 每个用户每天（UTC）的最大下注额
+    draw_schedule: Option<DrawSchedule>, // 未设置时每轮持续 ROUND_DURATION
 }
 
-impl Default for SystemStats {
+// 开奖时间表：按固定间隔连续开奖，或在每天/每周固定的 UTC 时间开奖
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DrawSchedule {
+    Interval { seconds: u64 }, // 上一轮结束后间隔 seconds 开奖
+    Daily { hour: u8, minute: u8 }, // 每天 hour:minute UTC
+    Weekly { weekday: u8, hour: u8, minute: u8 }, // weekday 0 = 周一 ... 6 = 周日
+}
+
+impl Default for LotteryConfig {
     fn default() -> Self {
         Self {
-            total_rounds: 0,
-            total_bets: 0,
-            total_winnings: 0,
-            active_users: 0,
-            total_ckbtc_deposits: 0,
+            // 默认保持单一中奖者拿走全部奖池
+            prize_distribution: PrizeDistribution::Tiered(vec![BPS_DENOMINATOR]),
+            house_fee_bps: 0,
+            jackpot_contribution_bps: 0,
+            jackpot_odds: 0,
+            min_participants: 0,
+            below_threshold_action: ThresholdAction::Extend,
+            max_tickets_per_user_per_round: 0,
+            max_tickets_per_round: 0,
+            max_daily_stake_per_user: 0,
+            draw_schedule: None,
         }
     }
 }
 
+// 平台费账户：开奖时累计，管理员可提取到任意 ICRC-1 账户
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct FeeAccount {
+    accrued: u64,
+    total_collected: u64,
+    total_withdrawn: u64,
+}
+
+// 流动性机器人账户：由管理员从平台金库（已累计的平台费）注资，与用户余额分开记账
 #[derive(CandidType, Deserialize, Serialize, Clone)]
-pub struct CkBtcDeposit {
-    principal: String, // Store as text for Candid compatibility
+pub struct BotAccount {
+    principal: Principal,
+    balance: u64,
+    tickets_per_round: u32, // 每轮自动购买的彩票数
+    total_funded: u64,
+    total_bet: u64,
+    total_won: u64,
+}
+
+// 单轮开奖结算结果：奖池去向（平台费、累积奖金注入、派奖、退款）
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Settlement {
+    round_id: u64,
+    prize_pool: u64,
+    house_fee: u64,
+    jackpot_contribution: u64,
+    prize_paid: u64,
+    jackpot_paid: u64,
+    refunded: u64,
+    winners: Vec<Principal>,
+}
+
+// 内部复式记账的账户
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LedgerAccount {
+    User(Principal),
+    Bot(Principal),
+    RoundPool, // 当前轮次奖池
+    Fees, // 平台费（平台金库）
+    Jackpot,
+    Treasury, // 外部资金的对手账户：充值从这里转入，提现转回这里
+}
+
+// 账户的累计借贷：余额 = credited - debited
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LedgerBalance {
+    credited: u64,
+    debited: u64,
+}
+
+// 一笔记账：从 from 转出 amount 记入 to
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Posting {
+    id: u64,
+    timestamp: u64,
+    from: LedgerAccount,
+    to: LedgerAccount,
     amount: u64,
-    tx_hash: String,
+    memo: String,
+}
+
+// 影子账本：与实际余额并行记账，用于核对不变量，不是余额的来源。
+// 流水只保留最近 MAX_LEDGER_JOURNAL 条，无法用来重建余额或完整审计历史
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct Ledger {
+    balances: BTreeMap<LedgerAccount, LedgerBalance>,
+    journal: BTreeMap<u64, Posting>, // 只保留最近 MAX_LEDGER_JOURNAL 条
+    next_posting_id: u64,
+}
+
+// 账本核对结果
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LedgerReport {
+    postings: u64,
+    accounts: u64,
+    internal_value: u64, // 除 Treasury 外所有账户的余额之和
+    violations: Vec<String>,
+}
+
+// 偿付能力对账报告：内部负债与链上持有的 ckBTC 对比
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SolvencyReport {
     timestamp: u64,
-    status: String, // "pending", "confirmed", "failed"
+    liabilities: u64, // 用户、机器人余额 + 奖池 + 平台费 + 累积奖金
+    treasury_balance: u64,
+    deposit_balances: u64, // 所有用户充值账户的链上余额之和
+    assets: u64,
+    accounts_checked: u64,
+    failed_queries: u64, // 查询失败的账户数，大于 0 时报告不完整，不解除暂停
+    solvent: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct SolvencyState {
+    reports: BTreeMap<u64, SolvencyReport>, // 按时间戳索引，只保留最近 MAX_SOLVENCY_REPORTS 份
+    paused: bool, // 负债超过资产时暂停下注和提现
+    consecutive_incomplete: Option<u64>, // 连续不完整的对账次数，达到 MAX_INCOMPLETE_SOLVENCY_CHECKS 时暂停
+}
+
+// 审计日志条目：特权操作和资金变动，只追加不修改
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AuditEntry {
+    id: u64,
+    timestamp: u64,
+    caller: Principal,
+    action: String,
+    arguments: String,
+    outcome: Result<String, String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AuditPage {
+    entries: Vec<AuditEntry>,
+    total: u64,
+}
+
+// 审计日志在堆上的索引：每条记录在稳定内存中的位置和操作名编号，升级后扫描稳定内存重建
+struct AuditIndex {
+    actions: Vec<String>,
+    entries: Vec<(u64, u16)>,
+    next_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    LedgerCall, // 调用 ckBTC ledger 失败（被拒绝或超时）
+    LedgerRejected, // ledger 返回了业务错误，例如转账失败
+    Invariant, // 内部一致性检查失败
+    Solvency, // 对账发现负债超过链上资产
+    ManagementCall, // 调用管理 canister 失败，例如 raw_rand
+}
+
+// 结构化错误事件，保存在有界环形缓冲区中
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ErrorEvent {
+    id: u64,
+    timestamp: u64,
+    component: String,
+    principal: Option<Principal>,
+    kind: ErrorKind,
+    message: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ErrorPage {
+    events: Vec<ErrorEvent>,
+    total: u64,
+}
+
+// 经过认证的查询结果：witness 中对应标签的叶子是 value 的 Candid 编码，
+// 前端校验 certificate 后比较 witness 的根哈希与 canister 的 certified_data
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CertifiedRound {
+    round: Round,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CertifiedDraw {
+    draw: Option<ArchivedRound>,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CertifiedStats {
+    stats: SystemStats,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+// HTTP 网关接口类型
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// 开奖随机数凭证：random_bytes 来自管理 canister 的 raw_rand，在本轮停止下注后获取；
+// 中奖者由前 8 字节得到的 seed 在本轮彩票账本上确定性地抽出，累积奖金由接下来 8 字节的 jackpot_seed 决定：next_random(jackpot_seed) % odds
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RandomnessProof {
+    seed: u64,
+    total_tickets: u64,
+    jackpot_odds: u64,
+    jackpot_roll: Option<u64>, // 累积奖金为空或未开启时没有抽签
+    jackpot_seed: Option<u64>, // 旧版本以时间戳为 seed 的轮次没有该字段
+    #[serde(serialize_with = "serialize_hex")] // JSON 接口中输出为十六进制字符串，而不是 32 个数字
+    random_bytes: Option<Vec<u8>>,
+}
+
+fn serialize_hex<S: serde::Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match bytes {
+        Some(bytes) => serializer.serialize_some(&bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        None => serializer.serialize_none(),
+    }
+}
+
+// 已结束轮次的归档记录
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ArchivedRound {
+    id: u64,
+    start_time: u64,
+    end_time: u64,
+    settled_at: u64,
+    total_tickets: u64,
+    participants: Vec<Participant>,
+    prize_pool: u64,
+    winners: Vec<HistoricalWinner>, // 按档位排序，累积奖金得主排在最后
+    randomness: Option<RandomnessProof>, // 未开奖（无人参与或退款）时为空
+    settlement: Settlement,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RoundPage {
+    rounds: Vec<ArchivedRound>,
+    total: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct WinnerPage {
+    winners: Vec<HistoricalWinner>,
+    total: u64,
+}
+
+// 累积奖金：每轮按比例注入，未开出时滚入下一轮
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct Jackpot {
+    balance: u64,
+    total_contributed: u64,
+    total_paid: u64,
+    last_won_round: Option<u64>,
 }
 
 const TICKET_PRICE: u64 = 1; // 0.00000001 ckBTC
 const ROUND_DURATION: u64 = 300_000_000_000; // 5 minutes
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_LEDGER_JOURNAL: usize = 1_000;
+const MAX_ERROR_EVENTS: usize = 500;
+const STABLE_MAGIC: &[u8; 8] = b"LOTTERY1"; // 稳定内存头部标记，旧版本的快照从偏移 0 开始，没有该标记
+const STABLE_HEADER_SIZE: u64 = 4_096;
+const WASM_PAGE_SIZE: u64 = 65_536;
+const SOLVENCY_CHECK_INTERVAL: u64 = 600; // seconds
+const MIN_DRAW_INTERVAL: u64 = 60; // seconds
+const MAX_DRAW_INTERVAL: u64 = 366 * 86_400; // seconds，最长一年开奖一次
+const UPCOMING_DRAWS_IN_ROUND: usize = 5;
+const DRAW_WATCHDOG_INTERVAL: u64 = 300; // seconds，开奖由 end_time 的一次性定时器触发，看门狗只补救错过的开奖
+const MAX_SOLVENCY_REPORTS: usize = 144; // 一天的对账报告
+const SOLVENCY_QUERY_BATCH: usize = 50; // 对账时并发查询的账户数
+const MAX_INCOMPLETE_SOLVENCY_CHECKS: u64 = 3; // 约半小时无法完成对账即按不偿付处理
+const DEPOSIT_SYNC_INTERVAL: u64 = 60; // seconds
+const DEPOSIT_SYNC_BATCH: usize = 50; // 每次定时同步的用户数
+const MIN_SWEEP_AMOUNT: u64 = 10 * CKBTC_TRANSFER_FEE; // 归集金额太小时不值得付手续费
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const LIMIT_INCREASE_DELAY: u64 = NANOS_PER_DAY; // 放宽限额的冷静期
+const BPS_DENOMINATOR: u64 = 10_000; // 100% = 10000 基点
+const MAX_PRIZE_TIERS: usize = 100;
+const MAX_DRAW_ATTEMPTS: usize = 32; // 抽到已中奖者时的最大重抽次数
+const MAX_HOUSE_FEE_BPS: u64 = 5_000; // 平台费上限 50%
+const MAX_JACKPOT_CONTRIBUTION_BPS: u64 = 5_000; // 与平台费上限相加不超过 100%
+const CKBTC_TRANSFER_FEE: u64 = 1_000; // 0.00001 ckBTC ledger fee
 const CKBTC_CANISTER_ID: &str = "mxzaz-hqaaa-aaaar-qaada-cai"; // Mainnet ckBTC canister
-const BALANCE_CHECK_INTERVAL: u64 = 60_000_000_000; // 1 minute in nanoseconds
-// 假用户 principal 常量
-const FAKE_USERS: [&str; 2] = [
+// ICRC-1 ckBTC canister interface
+type CkBtcCanister = candid::Principal;
+
+// 旧版本写死的假用户，余额没有真实资金支持，升级时删除
+const LEGACY_FAKE_USERS: [&str; 2] = [
     "mbge7-ijmh7-dt5e7-4e7un-ena3p-phmwu-7m5xb-jd4hr-4hdnh-hwxe6-jqe",
     "5kfak-kgkib-cc25b-bk5ck-33bpa-kdjns-dy7mk-o7ns3-p3ehy-ugyib-rqe",
 ];
 
-// 新增：随机选择假用户参与
-fn get_random_fake_users() -> Vec<Principal> {
-    let mut rng = time() as u32; // 使用时间作为随机种子
-    let fake_count = (rng % 2) + 1; // 随机选择1个假用户（0-1 + 1）
-    
-    let mut selected_fakes = Vec::new();
-    let mut used_indices = std::collections::HashSet::new();
-    
-    for _ in 0..fake_count {
-        let mut index;
-        loop {
-            index = (rng % FAKE_USERS.len() as u32) as usize;
-            rng = rng.wrapping_mul(1103515245).wrapping_add(12345); // 简单的线性同余生成器
-            if !used_indices.contains(&index) {
-                used_indices.insert(index);
-                break;
-            }
+// 旧版轮次格式（participants 为 vec principal），仅用于升级时迁移
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct LegacyRound {
+    id: u64,
+    participants: Vec<Principal>,
+    prize_pool: u64,
+    start_time: u64,
+    end_time: u64,
+    winners: Vec<Principal>,
+}
+
+impl From<LegacyRound> for Round {
+    fn from(legacy: LegacyRound) -> Self {
+        let mut round = Self {
+            id: legacy.id,
+            participants: vec![],
+            ticket_ranges: vec![],
+            total_tickets: 0,
+            prize_pool: legacy.prize_pool,
+            start_time: legacy.start_time,
+            end_time: legacy.end_time,
+            winners: legacy.winners,
+            jackpot: None,
+            upcoming_draws: None,
+            status: Some(RoundStatus::Open),
+        };
+        for principal in legacy.participants {
+            add_tickets(&mut round, principal, false, 1);
         }
-        
-        if let Ok(principal) = Principal::from_text(FAKE_USERS[index]) {
-            selected_fakes.push(principal);
+        round
+    }
+}
+
+// 持久化的轮次参与者；ticket_count 在早期版本中不存在，视为一张彩票
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StoredParticipant {
+    principal: Principal,
+    is_bot: bool,
+    ticket_count: Option<u64>,
+}
+
+// 持久化的轮次：Round 的字段随版本逐步增加，后加的字段都用 opt，旧版本保存的轮次仍能反序列化
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StoredRound {
+    id: u64,
+    participants: Vec<StoredParticipant>,
+    ticket_ranges: Option<Vec<TicketRange>>,
+    total_tickets: Option<u64>,
+    prize_pool: u64,
+    start_time: u64,
+    end_time: u64,
+    winners: Vec<Principal>,
+    status: Option<RoundStatus>,
+}
+
+impl From<Round> for StoredRound {
+    fn from(round: Round) -> Self {
+        Self {
+            id: round.id,
+            participants: round.participants.into_iter().map(|p| StoredParticipant {
+                principal: p.principal,
+                is_bot: p.is_bot,
+                ticket_count: Some(p.ticket_count),
+            }).collect(),
+            ticket_ranges: Some(round.ticket_ranges),
+            total_tickets: Some(round.total_tickets),
+            prize_pool: round.prize_pool,
+            start_time: round.start_time,
+            end_time: round.end_time,
+            winners: round.winners,
+            status: round.status,
         }
     }
-    
-    ic_cdk::println!("🎲 [RANDOM_FAKE_USERS] Selected {} fake users for this round", selected_fakes.len());
-    selected_fakes
 }
 
-// 新增：初始化假用户函数
-fn initialize_fake_users() {
-    ic_cdk::println!("🤖 [INIT_FAKE_USERS] Initializing fake users...");
-    
-    USERS.with(|users| {
-        let mut users_ref = users.borrow_mut();
-        
-        for fake_principal_str in FAKE_USERS.iter() {
-            if let Ok(fake_principal) = Principal::from_text(fake_principal_str) {
-                if !users_ref.contains_key(&fake_principal) {
-                    // 为假用户创建账户，分配初始余额
-                    let deposit_account = Account {
-                        owner: fake_principal,
-                        subaccount: Some(fake_principal.as_slice().to_vec()),
-                    };
-                    
-                    users_ref.insert(fake_principal, User {
-                        balance: 1000, // 给假用户1000 e8s初始余额
-                        transaction_history: vec![],
-                        winning_history: vec![],
-                        deposit_account,
-                        principal_text: fake_principal.to_string(),
-                        last_balance_check: time(),
-                    });
-                    
-                    ic_cdk::println!("🤖 [INIT_FAKE_USERS] Created fake user: {} with balance: 1000 e8s", fake_principal);
-                } else {
-                    ic_cdk::println!("🤖 [INIT_FAKE_USERS] Fake user already exists: {}", fake_principal);
+impl From<StoredRound> for Round {
+    fn from(stored: StoredRound) -> Self {
+        let mut round = Self {
+            id: stored.id,
+            participants: vec![],
+            ticket_ranges: vec![],
+            total_tickets: 0,
+            prize_pool: stored.prize_pool,
+            start_time: stored.start_time,
+            end_time: stored.end_time,
+            winners: stored.winners,
+            jackpot: None,
+            upcoming_draws: None,
+            status: stored.status,
+        };
+        match stored.ticket_ranges {
+            Some(ticket_ranges) => {
+                round.total_tickets = stored.total_tickets.unwrap_or_else(|| ticket_ranges.last().map(|r| r.end).unwrap_or(0));
+                round.ticket_ranges = ticket_ranges;
+                round.participants = stored.participants.into_iter().map(|p| Participant {
+                    principal: p.principal,
+                    is_bot: p.is_bot,
+                    ticket_count: p.ticket_count.unwrap_or(1),
+                    win_probability: 0.0,
+                }).collect();
+            }
+            // 没有彩票账本的旧版本：按参与记录重建（同一用户的多条记录合并）
+            None => {
+                for p in stored.participants {
+                    add_tickets(&mut round, p.principal, p.is_bot, p.ticket_count.unwrap_or(1));
                 }
             }
         }
-    });
-    
-    save_to_stable_storage();
+        round
+    }
 }
 
-// ICRC-1 ckBTC canister interface
-type CkBtcCanister = candid::Principal;
-
 // 稳定的数据结构，用于持久化存储
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(CandidType, Deserialize, Serialize, Default)]
 struct StableStorage {
-    users: HashMap<Principal, User>,
-    current_round: Round,
+    users: HashMap<Principal, LegacyUser>, // 旧格式，迁移后保存为空
+    current_round: Option<LegacyRound>, // 旧格式，仅在升级迁移时读取
+    round: Option<StoredRound>,
     stats: SystemStats,
     admin: Option<Principal>,
     ckbtc_deposits: HashMap<String, CkBtcDeposit>,
     historical_winners: Vec<HistoricalWinner>, // 历史中奖记录
-}
-
-impl Default for StableStorage {
-    fn default() -> Self {
-        Self {
-            users: HashMap::new(),
-            current_round: Round::default(),
-            stats: SystemStats::default(),
-            admin: None,
-            ckbtc_deposits: HashMap::new(),
-            historical_winners: Vec::new(),
-        }
-    }
+    // 新增字段使用 opt，旧版本保存的数据缺少该字段时仍能正常反序列化
+    config: Option<LotteryConfig>,
+    fee_account: Option<FeeAccount>,
+    jackpot: Option<Jackpot>,
+    bots: Option<HashMap<Principal, BotAccount>>,
+    accounts: Option<HashMap<Principal, User>>,
+    transaction_log: Option<BTreeMap<(Principal, u64), LegacyTransaction>>, // 旧格式，迁移后保存为空
+    transactions: Option<BTreeMap<(Principal, u64), Transaction>>,
+    round_archive: Option<BTreeMap<u64, ArchivedRound>>,
+    ledger: Option<Ledger>,
+    solvency: Option<SolvencyState>,
+    audit_log: Option<BTreeMap<u64, AuditEntry>>, // 旧格式，审计日志现在直接写入稳定内存
+    auditors: Option<Vec<Principal>>,
+    error_log: Option<BTreeMap<u64, ErrorEvent>>,
+    error_counts: Option<BTreeMap<(String, ErrorKind), u64>>,
 }
 
 thread_local! {
-    static ADMIN: std::cell::RefCell<Option<Principal>> = std::cell::RefCell::new(None);
+    static ADMIN: std::cell::RefCell<Option<Principal>> = const { std::cell::RefCell::new(None) };
     static USERS: std::cell::RefCell<HashMap<Principal, User>> = std::cell::RefCell::new(HashMap::new());
     static CURRENT_ROUND: std::cell::RefCell<Round> = std::cell::RefCell::new(Round {
         id: 0,
         participants: vec![],
+        ticket_ranges: vec![],
+        total_tickets: 0,
         prize_pool: 0,
         start_time: time(),
         end_time: time() + ROUND_DURATION,
         winners: vec![],
+        jackpot: None,
+        upcoming_draws: None,
+        status: Some(RoundStatus::Open),
     });
-    static STATS: std::cell::RefCell<SystemStats> = std::cell::RefCell::new(SystemStats {
-        total_rounds: 0,
-        total_bets: 0,
-        total_winnings: 0,
-        active_users: 0,
-        total_ckbtc_deposits: 0,
-    });
-    static TIMER_INITIALIZED: std::cell::RefCell<bool> = std::cell::RefCell::new(false);
+    static STATS: std::cell::RefCell<SystemStats> = std::cell::RefCell::new(SystemStats::default());
+    static DRAW_TIMER: std::cell::RefCell<Option<TimerId>> = const { std::cell::RefCell::new(None) }; // 当前轮次 end_time 的一次性开奖定时器
     static CKBTC_DEPOSITS: std::cell::RefCell<HashMap<String, CkBtcDeposit>> = std::cell::RefCell::new(HashMap::new());
-    static HISTORICAL_WINNERS: std::cell::RefCell<Vec<HistoricalWinner>> = std::cell::RefCell::new(Vec::new());
+    static HISTORICAL_WINNERS: std::cell::RefCell<Vec<HistoricalWinner>> = const { std::cell::RefCell::new(Vec::new()) };
+    static ROUND_ARCHIVE: std::cell::RefCell<BTreeMap<u64, ArchivedRound>> = const { std::cell::RefCell::new(BTreeMap::new()) };
+    static LEDGER: std::cell::RefCell<Ledger> = std::cell::RefCell::new(Ledger::default());
+    static SOLVENCY: std::cell::RefCell<SolvencyState> = std::cell::RefCell::new(SolvencyState::default());
+    static AUDIT_INDEX: std::cell::RefCell<AuditIndex> = const { std::cell::RefCell::new(AuditIndex { actions: Vec::new(), entries: Vec::new(), next_id: 0 }) };
+    static STABLE_LOG_END: std::cell::Cell<u64> = const { std::cell::Cell::new(STABLE_HEADER_SIZE) }; // 稳定内存中审计日志的结束位置
+    static ERROR_LOG: std::cell::RefCell<BTreeMap<u64, ErrorEvent>> = const { std::cell::RefCell::new(BTreeMap::new()) };
+    static ERROR_COUNTS: std::cell::RefCell<BTreeMap<(String, ErrorKind), u64>> = const { std::cell::RefCell::new(BTreeMap::new()) }; // 按组件和类型累计，不随环形缓冲区淘汰
+    static CERTIFIED_TREE: std::cell::RefCell<RbTree<&'static str, Vec<u8>>> = const { std::cell::RefCell::new(RbTree::new()) }; // 轮次、最近开奖和统计的认证数据
+    static DEPOSIT_SYNC_CURSOR: std::cell::RefCell<Option<Principal>> = const { std::cell::RefCell::new(None) }; // 上一批同步到的最后一个用户，不需要持久化
+    static DEPOSIT_WITHDRAWALS_IN_FLIGHT: std::cell::RefCell<BTreeMap<Principal, u32>> = const { std::cell::RefCell::new(BTreeMap::new()) }; // 正在从各用户充值账户转出（提现或归集）的笔数，期间不同步该账户
+    static WITHDRAWALS_IN_FLIGHT: std::cell::RefCell<(u64, u64)> = const { std::cell::RefCell::new((0, 0)) }; // 正在等待 ledger 转账结果的提现（笔数，金额）
+    static AUDITORS: std::cell::RefCell<Vec<Principal>> = const { std::cell::RefCell::new(Vec::new()) };
+    static CONFIG: std::cell::RefCell<LotteryConfig> = std::cell::RefCell::new(LotteryConfig::default());
+    static FEE_ACCOUNT: std::cell::RefCell<FeeAccount> = std::cell::RefCell::new(FeeAccount::default());
+    static JACKPOT: std::cell::RefCell<Jackpot> = std::cell::RefCell::new(Jackpot::default());
+    static BOTS: std::cell::RefCell<HashMap<Principal, BotAccount>> = std::cell::RefCell::new(HashMap::new());
+    // 全局只追加交易日志，按 (用户, 序号) 索引
+    static TRANSACTION_LOG: std::cell::RefCell<BTreeMap<(Principal, u64), Transaction>> = const { std::cell::RefCell::new(BTreeMap::new()) };
 }
 
 // 稳定的存储变量
@@ -287,25 +938,133 @@ static mut STABLE_STORAGE: Option<StableStorage> = None;
 // 获取稳定存储的引用
 fn get_stable_storage() -> &'static mut StableStorage {
     unsafe {
-        if STABLE_STORAGE.is_none() {
-            STABLE_STORAGE = Some(StableStorage::default());
-        }
-        STABLE_STORAGE.as_mut().unwrap()
+        let storage = &mut *std::ptr::addr_of_mut!(STABLE_STORAGE);
+        storage.get_or_insert_with(StableStorage::default)
     }
 }
 
 // 从稳定存储加载数据到线程本地存储
+// 稳定内存布局：
+// [0, STABLE_HEADER_SIZE) 头部：magic、审计日志结束位置、升级快照的位置和长度（各 8 字节）
+// [STABLE_HEADER_SIZE, 日志结束位置) 只追加的审计日志，每条为 u32 长度 + u8 操作名长度 + 操作名 + Candid 编码的 AuditEntry
+// 日志结束位置之后是 pre_upgrade 写入的快照，升级完成后继续追加日志会覆盖它
+fn stable_grow_to(end: u64) {
+    let pages = end.div_ceil(WASM_PAGE_SIZE);
+    let size = ic_cdk::api::stable::stable_size();
+    if pages > size {
+        ic_cdk::api::stable::stable_grow(pages - size).expect("Failed to grow stable memory");
+    }
+}
+
+fn stable_read_u64(offset: u64) -> u64 {
+    let mut buf = [0u8; 8];
+    ic_cdk::api::stable::stable_read(offset, &mut buf);
+    u64::from_le_bytes(buf)
+}
+
+fn stable_write_u64(offset: u64, value: u64) {
+    ic_cdk::api::stable::stable_write(offset, &value.to_le_bytes());
+}
+
+fn has_stable_header() -> bool {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 8];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    &magic == STABLE_MAGIC
+}
+
+// 新增：写入空的头部，审计日志从头部之后开始
+fn init_stable_layout() {
+    stable_grow_to(STABLE_HEADER_SIZE);
+    ic_cdk::api::stable::stable_write(0, STABLE_MAGIC);
+    STABLE_LOG_END.with(|end| end.set(STABLE_HEADER_SIZE));
+    stable_write_u64(8, STABLE_HEADER_SIZE);
+    stable_write_u64(16, 0);
+    stable_write_u64(24, 0);
+}
+
+// 新增：追加一条审计记录并更新索引和头部中的日志结束位置
+fn append_audit_entry(entry: &AuditEntry) {
+    let payload = candid::encode_one(entry).expect("Failed to encode audit entry");
+    let action = &entry.action.as_bytes()[..entry.action.len().min(u8::MAX as usize)];
+    let offset = STABLE_LOG_END.with(|end| end.get());
+    let mut record = Vec::with_capacity(5 + action.len() + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.push(action.len() as u8);
+    record.extend_from_slice(action);
+    record.extend_from_slice(&payload);
+    
+    let end = offset + record.len() as u64;
+    stable_grow_to(end);
+    ic_cdk::api::stable::stable_write(offset, &record);
+    STABLE_LOG_END.with(|e| e.set(end));
+    stable_write_u64(8, end);
+    index_audit_record(offset, String::from_utf8_lossy(action).into_owned(), entry.id);
+}
+
+fn index_audit_record(offset: u64, action: String, id: u64) {
+    AUDIT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let action_id = match index.actions.iter().position(|known| *known == action) {
+            Some(action_id) => action_id,
+            None => {
+                index.actions.push(action);
+                index.actions.len() - 1
+            }
+        };
+        index.entries.push((offset, action_id as u16));
+        index.next_id = index.next_id.max(id + 1);
+    });
+}
+
+fn read_audit_entry(offset: u64) -> AuditEntry {
+    let mut header = [0u8; 5];
+    ic_cdk::api::stable::stable_read(offset, &mut header);
+    let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let mut payload = vec![0u8; payload_len];
+    ic_cdk::api::stable::stable_read(offset + 5 + header[4] as u64, &mut payload);
+    candid::decode_one(&payload).expect("Failed to decode audit entry")
+}
+
+// 新增：升级后只读取每条记录的头部重建索引，最后一条记录决定下一个编号
+fn rebuild_audit_index() {
+    let end = stable_read_u64(8);
+    STABLE_LOG_END.with(|e| e.set(end));
+    let mut offset = STABLE_HEADER_SIZE;
+    let mut last = None;
+    while offset < end {
+        let mut header = [0u8; 5];
+        ic_cdk::api::stable::stable_read(offset, &mut header);
+        let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut action = vec![0u8; header[4] as usize];
+        ic_cdk::api::stable::stable_read(offset + 5, &mut action);
+        index_audit_record(offset, String::from_utf8_lossy(&action).into_owned(), 0);
+        last = Some(offset);
+        offset += 5 + header[4] as u64 + payload_len;
+    }
+    if let Some(last) = last {
+        let next_id = read_audit_entry(last).id + 1;
+        AUDIT_INDEX.with(|index| index.borrow_mut().next_id = next_id);
+    }
+}
+
 fn load_from_stable_storage() {
     let stable = get_stable_storage();
     
-    // 加载用户数据
-    USERS.with(|users| {
-        *users.borrow_mut() = stable.users.clone();
-    });
-    
-    // 加载当前轮次
+    // 加载当前轮次（兼容旧格式）
     CURRENT_ROUND.with(|round| {
-        *round.borrow_mut() = stable.current_round.clone();
+        if let Some(current) = stable.round.clone() {
+            *round.borrow_mut() = current.into();
+        } else if let Some(legacy) = stable.current_round.clone() {
+            *round.borrow_mut() = legacy.into();
+        }
+        // 进行中的调用不会跨升级保留：开奖中的轮次回到 Closing，由定时器重新获取随机数
+        let mut round = round.borrow_mut();
+        if round.status() == RoundStatus::Drawing {
+            round.transition(RoundStatus::Closing);
+        }
     });
     
     // 加载统计信息
@@ -327,20 +1086,172 @@ fn load_from_stable_storage() {
     HISTORICAL_WINNERS.with(|winners| {
         *winners.borrow_mut() = stable.historical_winners.clone();
     });
+    
+    // 加载轮次归档
+    ROUND_ARCHIVE.with(|archive| {
+        *archive.borrow_mut() = stable.round_archive.clone().unwrap_or_default();
+    });
+    
+    // 加载奖池配置
+    CONFIG.with(|config| {
+        *config.borrow_mut() = stable.config.clone().unwrap_or_default();
+    });
+    
+    // 加载平台费账户
+    FEE_ACCOUNT.with(|fees| {
+        *fees.borrow_mut() = stable.fee_account.clone().unwrap_or_default();
+    });
+    
+    // 加载累积奖金
+    JACKPOT.with(|jackpot| {
+        *jackpot.borrow_mut() = stable.jackpot.clone().unwrap_or_default();
+    });
+    
+    // 加载流动性机器人
+    BOTS.with(|bots| {
+        *bots.borrow_mut() = stable.bots.clone().unwrap_or_default();
+    });
+    
+    // 加载用户数据和交易日志（兼容交易历史内嵌在用户记录中的旧格式）
+    // 依赖已加载的轮次和归档来推断旧交易所属的轮次
+    TRANSACTION_LOG.with(|log| {
+        let mut log_ref = log.borrow_mut();
+        *log_ref = stable.transactions.clone().unwrap_or_default();
+        for ((principal, seq), legacy) in stable.transaction_log.clone().unwrap_or_default() {
+            let winning_history = stable.accounts.as_ref()
+                .and_then(|accounts| accounts.get(&principal))
+                .map(|user| user.winning_history.as_slice())
+                .unwrap_or(&[]);
+            log_ref.insert((principal, seq), migrate_transaction(legacy, winning_history));
+        }
+    });
+    USERS.with(|users| {
+        let mut users_ref = users.borrow_mut();
+        *users_ref = stable.accounts.clone().unwrap_or_default();
+        for (principal, legacy) in stable.users.clone() {
+            let mut user = User {
+                balance: legacy.balance,
+                transaction_count: 0,
+                total_deposited: 0,
+                total_wagered: 0,
+                total_won: 0,
+                total_withdrawn: 0,
+                winning_history: legacy.winning_history,
+                deposit_account: legacy.deposit_account,
+                principal_text: legacy.principal_text,
+                last_balance_check: legacy.last_balance_check,
+                daily_stake: legacy.daily_stake,
+                responsible_gambling: legacy.responsible_gambling,
+                deposit_watermarks: vec![],
+            };
+            for transaction in legacy.transaction_history {
+                let transaction = migrate_transaction(transaction, &user.winning_history);
+                record_transaction(principal, &mut user, transaction);
+            }
+            users_ref.insert(principal, user);
+        }
+        
+        // 旧版本的充值账户归用户所有（canister 无法转出），改为 canister 名下的子账户，
+        // 新账户此前不可能有入账，高水位从零开始
+        for (principal, user) in users_ref.iter_mut() {
+            let deposit_account = deposit_account_for(*principal);
+            if user.deposit_account != deposit_account {
+                user.deposit_account = deposit_account.clone();
+                user.deposit_watermarks = vec![DepositWatermark {
+                    account: deposit_account,
+                    credited: 0,
+                    outflows: 0,
+                    last_synced: time(),
+                }];
+            }
+        }
+    });
+    
+    // 加载偿付能力对账状态
+    SOLVENCY.with(|solvency| {
+        *solvency.borrow_mut() = stable.solvency.clone().unwrap_or_default();
+    });
+    
+    // 旧版本把审计日志保存在快照中：按原编号顺序追加到稳定内存
+    for entry in stable.audit_log.take().unwrap_or_default().into_values() {
+        append_audit_entry(&entry);
+    }
+    AUDITORS.with(|auditors| {
+        *auditors.borrow_mut() = stable.auditors.clone().unwrap_or_default();
+    });
+    
+    // 加载错误日志
+    ERROR_LOG.with(|log| {
+        *log.borrow_mut() = stable.error_log.clone().unwrap_or_default();
+    });
+    ERROR_COUNTS.with(|counts| {
+        *counts.borrow_mut() = stable.error_counts.clone().unwrap_or_default();
+    });
+    
+    // 加载复式账本；旧版本没有账本时按当前余额开账
+    LEDGER.with(|ledger| {
+        *ledger.borrow_mut() = stable.ledger.clone().unwrap_or_default();
+    });
+    if stable.ledger.is_none() {
+        open_ledger();
+    }
+    
+    remove_legacy_fake_users();
+}
+
+// 新增：删除旧版本的假用户：撤下其在当前轮次的彩票（奖池同步扣减），删除账户和交易记录，
+// 并把这部分没有资金支持的余额从账本中冲回
+fn remove_legacy_fake_users() {
+    for principal in LEGACY_FAKE_USERS.iter().filter_map(|p| Principal::from_text(p).ok()) {
+        let Some(user) = USERS.with(|users| users.borrow_mut().remove(&principal)) else {
+            continue;
+        };
+        
+        let stake = CURRENT_ROUND.with(|r| {
+            let mut round = r.borrow_mut();
+            let tickets = find_participant(&round, &principal, false).map(|p| p.ticket_count).unwrap_or(0);
+            if tickets > 0 {
+                let remaining: Vec<Participant> = round.participants.drain(..).filter(|p| p.is_bot || p.principal != principal).collect();
+                round.ticket_ranges.clear();
+                round.total_tickets = 0;
+                for participant in remaining {
+                    add_tickets(&mut round, participant.principal, participant.is_bot, participant.ticket_count);
+                }
+            }
+            let stake = (tickets * TICKET_PRICE).min(round.prize_pool);
+            round.prize_pool -= stake;
+            stake
+        });
+        
+        TRANSACTION_LOG.with(|log| {
+            log.borrow_mut().retain(|(owner, _), _| *owner != principal);
+        });
+        post(LedgerAccount::User(principal), LedgerAccount::Treasury, user.balance, "legacy fake user removed".to_string());
+        post(LedgerAccount::RoundPool, LedgerAccount::Treasury, stake, "legacy fake user tickets removed".to_string());
+        
+        ic_cdk::println!("🧹 [MIGRATION] Removed legacy fake user {} with {} e8s balance and {} e8s in the current round", principal, user.balance, stake);
+    }
 }
 
-// 保存数据到稳定存储
+// 保存数据到稳定存储：只在 pre_upgrade 中调用，避免每次更新都复制全部日志
+// 只在 pre_upgrade 中调用，之后堆内存会被丢弃，所以较大的日志直接移出而不是复制
 fn save_to_stable_storage() {
     let stable = get_stable_storage();
     
-    // 保存用户数据
+    // 保存用户数据和交易日志
     USERS.with(|users| {
-        stable.users = users.borrow().clone();
+        stable.accounts = Some(users.borrow().clone());
+        stable.users = HashMap::new();
+    });
+    TRANSACTION_LOG.with(|log| {
+        stable.transactions = Some(std::mem::take(&mut *log.borrow_mut()));
+        stable.transaction_log = None;
     });
     
     // 保存当前轮次
     CURRENT_ROUND.with(|round| {
-        stable.current_round = round.borrow().clone();
+        stable.round = Some(round.borrow().clone().into());
+        stable.current_round = None;
     });
     
     // 保存统计信息
@@ -360,7 +1271,56 @@ fn save_to_stable_storage() {
     
     // 保存历史中奖记录
     HISTORICAL_WINNERS.with(|winners| {
-        stable.historical_winners = winners.borrow().clone();
+        stable.historical_winners = std::mem::take(&mut *winners.borrow_mut());
+    });
+    
+    // 保存轮次归档
+    ROUND_ARCHIVE.with(|archive| {
+        stable.round_archive = Some(std::mem::take(&mut *archive.borrow_mut()));
+    });
+    
+    // 保存复式账本
+    LEDGER.with(|ledger| {
+        stable.ledger = Some(ledger.borrow().clone());
+    });
+    
+    // 保存偿付能力对账状态
+    SOLVENCY.with(|solvency| {
+        stable.solvency = Some(solvency.borrow().clone());
+    });
+    
+    // 保存审计员（审计日志已在稳定内存中）
+    stable.audit_log = None;
+    AUDITORS.with(|auditors| {
+        stable.auditors = Some(auditors.borrow().clone());
+    });
+    
+    // 保存错误日志
+    ERROR_LOG.with(|log| {
+        stable.error_log = Some(log.borrow().clone());
+    });
+    ERROR_COUNTS.with(|counts| {
+        stable.error_counts = Some(counts.borrow().clone());
+    });
+    
+    // 保存奖池配置
+    CONFIG.with(|config| {
+        stable.config = Some(config.borrow().clone());
+    });
+    
+    // 保存平台费账户
+    FEE_ACCOUNT.with(|fees| {
+        stable.fee_account = Some(fees.borrow().clone());
+    });
+    
+    // 保存累积奖金
+    JACKPOT.with(|jackpot| {
+        stable.jackpot = Some(jackpot.borrow().clone());
+    });
+    
+    // 保存流动性机器人
+    BOTS.with(|bots| {
+        stable.bots = Some(bots.borrow().clone());
     });
 }
 
@@ -369,34 +1329,61 @@ fn pre_upgrade() {
     // 在升级前保存数据到稳定存储
     save_to_stable_storage();
     
-    // 将稳定存储序列化到 stable memory
-    let stable = get_stable_storage();
-    storage::stable_save((stable,)).expect("Failed to save to stable memory");
+    // 快照写在审计日志之后，并在头部记录位置和长度
+    let bytes = candid::encode_one(&*get_stable_storage()).expect("Failed to save to stable memory");
+    let offset = STABLE_LOG_END.with(|end| end.get());
+    stable_grow_to(offset + bytes.len() as u64);
+    ic_cdk::api::stable::stable_write(offset, &bytes);
+    stable_write_u64(16, offset);
+    stable_write_u64(24, bytes.len() as u64);
 }
 
 #[post_upgrade]
 fn post_upgrade() {
     // 尝试从 stable memory 反序列化数据，如果失败则用默认值
-    let stable_result: Result<(StableStorage,), _> = storage::stable_restore();
-    let stable = match stable_result {
-        Ok((stable,)) => stable,
-        Err(_) => StableStorage::default(),
+    let stable_result: Result<StableStorage, String> = if has_stable_header() {
+        rebuild_audit_index();
+        let mut bytes = vec![0u8; stable_read_u64(24) as usize];
+        ic_cdk::api::stable::stable_read(stable_read_u64(16), &mut bytes);
+        candid::decode_one(&bytes).map_err(|e| e.to_string())
+    } else {
+        // 旧版本的快照从偏移 0 开始；读出后改用新布局，之后追加的日志会覆盖旧快照
+        let legacy: Result<(StableStorage,), String> = storage::stable_restore();
+        init_stable_layout();
+        legacy.map(|(stable,)| stable)
     };
+    let stable = stable_result.unwrap_or_default();
     unsafe {
         STABLE_STORAGE = Some(stable);
     }
     load_from_stable_storage();
-    ensure_timer_initialized();
+    // 数据已载入线程本地存储，释放快照，避免整个状态在堆上常驻两份
+    unsafe {
+        STABLE_STORAGE = None;
+    }
+    refresh_certified_data(); // 升级会清空 certified_data
+    arm_timers(); // 升级会清空所有定时器，按持久化的状态重新设置
+}
+
+#[init]
+fn init() {
+    init_stable_layout();
+    refresh_certified_data();
+    arm_timers();
 }
 
 #[update]
 pub fn initialize_auth() {
     let caller = ic_cdk::caller();
-    ADMIN.with(|a| {
+    let claimed = ADMIN.with(|a| {
         if a.borrow().is_none() {
             *a.borrow_mut() = Some(caller);
+            true
+        } else {
+            false
         }
     });
+    audit("initialize_auth", String::new(), if claimed { Ok("admin set".to_string()) } else { Err("admin already set".to_string()) });
     
     // 设置统一资金账户为管理员账户
     // TREASURY_ACCOUNT.with(|treasury| { // 删除
@@ -405,736 +1392,2088 @@ pub fn initialize_auth() {
     //         subaccount: None, // 删除
     //     }; // 删除
     // }); // 删除
-    
-    // Initialize timer
-    initialize_timer();
-    
-    // 保存数据到稳定存储
-    save_to_stable_storage();
 }
 
-// 自动初始化定时器，确保轮次能正常进行
-fn ensure_timer_initialized() {
-    TIMER_INITIALIZED.with(|initialized| {
-        if !*initialized.borrow() {
-            initialize_timer();
-        }
+// 设置所有定时任务，只在 init 和 post_upgrade 中调用；不修改任何用户数据
+fn arm_timers() {
+    // 在当前轮次的 end_time 准时开奖
+    schedule_draw_timer();
+    
+    // 低频看门狗，补救错过的开奖
+    set_timer_interval(Duration::from_secs(DRAW_WATCHDOG_INTERVAL), || {
+        ic_cdk::spawn(async {
+            check_and_auto_draw().await;
+        });
+    });
+    
+    // 定期核对内部负债与链上资产
+    set_timer_interval(Duration::from_secs(SOLVENCY_CHECK_INTERVAL), || {
+        ic_cdk::spawn(async {
+            reconcile_solvency().await;
+        });
+    });
+    
+    // 分批索引用户充值账户的新入账
+    set_timer_interval(Duration::from_secs(DEPOSIT_SYNC_INTERVAL), || {
+        ic_cdk::spawn(async {
+            sync_deposit_batch().await;
+        });
     });
 }
 
-fn initialize_timer() {
-    TIMER_INITIALIZED.with(|initialized| {
-        if !*initialized.borrow() {
-            // 初始化假用户
-            initialize_fake_users();
-            
-            // Set timer to check round status every 30 seconds (更频繁的检查)
-            set_timer_interval(Duration::from_secs(30), || {
-                ic_cdk::spawn(async {
-                    check_and_auto_draw().await;
-                });
-            });
-            
-            *initialized.borrow_mut() = true;
-        }
+// 新增：为当前轮次设置在 end_time 触发的一次性定时器，替换之前设置的定时器
+fn schedule_draw_timer() {
+    let (round_id, end_time) = CURRENT_ROUND.with(|r| {
+        let round = r.borrow();
+        (round.id, round.end_time)
+    });
+    let delay = Duration::from_nanos(end_time.saturating_sub(time()));
+    let timer_id = set_timer(delay, || {
+        DRAW_TIMER.with(|t| *t.borrow_mut() = None);
+        ic_cdk::spawn(async {
+            check_and_auto_draw().await;
+        });
     });
+    if let Some(previous) = DRAW_TIMER.with(|t| t.borrow_mut().replace(timer_id)) {
+        clear_timer(previous);
+    }
+    ic_cdk::println!("⏰ [DRAW_TIMER] Round {} draw scheduled in {}s", round_id, delay.as_secs());
 }
 
 async fn check_and_auto_draw() {
     let current_time = time();
-    let round_info = CURRENT_ROUND.with(|r| {
+    let (round_id, should_draw) = CURRENT_ROUND.with(|r| {
         let round = r.borrow();
-        (round.clone(), current_time >= round.end_time)
+        let should_draw = match round.status() {
+            RoundStatus::Open => current_time >= round.end_time,
+            RoundStatus::Closing => true,
+            RoundStatus::Drawing | RoundStatus::Settled => false, // 开奖正在进行
+        };
+        (round.id, should_draw)
     });
     
-    let (round, should_draw) = round_info;
-    
     if should_draw {
-        ic_cdk::println!("🎲 [AUTO_DRAW] Round {} ended, starting auto draw...", round.id);
-        auto_draw_winner();
-        
-        // 保存数据到稳定存储
-        save_to_stable_storage();
-        
-        ic_cdk::println!("✅ [AUTO_DRAW] Auto draw completed, new round {} started", round.id + 1);
+        ic_cdk::println!("🎲 [AUTO_DRAW] Round {} ended, starting auto draw...", round_id);
+        match draw_current_round(false).await {
+            Ok(Some(_)) => ic_cdk::println!("✅ [AUTO_DRAW] Auto draw completed, new round {} started", round_id + 1),
+            Ok(None) => ic_cdk::println!("⏳ [AUTO_DRAW] Round {} extended below participant threshold", round_id),
+            Err(e) => ic_cdk::println!("❌ [AUTO_DRAW] {}", e),
+        }
+    } else if DRAW_TIMER.with(|t| t.borrow().is_none()) {
+        // 定时器提前触发或丢失时按 end_time 重新设置
+        schedule_draw_timer();
     }
 }
 
-fn auto_draw_winner() {
-    let winner = CURRENT_ROUND.with(|r| {
-        let mut round = r.borrow_mut();
-        if round.participants.is_empty() {
-            ic_cdk::println!("🎲 [AUTO_DRAW] No participants in round {}, starting new round", round.id);
-            return Some(round.clone());
-        }
-        let idx = time() as usize % round.participants.len();
-        let winner = round.participants[idx];
-        round.winners = vec![winner];
-        ic_cdk::println!("🎲 [AUTO_DRAW] Winner selected: {} from {} participants", winner, round.participants.len());
-        Some(round.clone())
+// 新增：偿付能力对账：汇总内部负债，查询金库和每个充值账户的链上余额，保存报告；
+// 负债超过资产时暂停下注和提现，恢复偿付能力后自动解除。用户余额只来自链上已验证的入账，调用者无法凭空抬高负债
+async fn reconcile_solvency() -> SolvencyReport {
+    let treasury = Account { owner: ic_cdk::id(), subaccount: None };
+    // 只有本 canister 名下的充值子账户才是平台资产
+    let mut deposit_subaccounts: Vec<Vec<u8>> = USERS.with(|users| {
+        users.borrow().values().filter_map(|user| user.deposit_account.subaccount.clone()).collect()
     });
+    deposit_subaccounts.sort();
+    deposit_subaccounts.dedup();
     
-    if let Some(winner) = winner {
-        if !winner.participants.is_empty() {
-            // 计算总奖池（包括假用户）
-            let total_prize_pool = winner.participants.len() as u64 * TICKET_PRICE;
-            
-            USERS.with(|users| {
-                let mut users_ref = users.borrow_mut();
-                if let Some(user) = users_ref.get_mut(&winner.winners[0]) {
-                    let old_balance = user.balance;
-                    user.balance += total_prize_pool;  // 使用总奖池
-                    user.transaction_history.push(Transaction {
-                        amount: total_prize_pool,
-                        timestamp: time(),
-                        transaction_type: "Win".to_string(),
-                        tx_hash: None,
-                        ckbtc_address: None,
-                    });
-                    user.winning_history.push(Winning {
-                        amount: total_prize_pool,
-                        timestamp: time(),
-                        round_id: winner.id,
-                    });
-                    ic_cdk::println!("🎉 [AUTO_DRAW] Winner {} received {} e8s prize (total pool, balance: {} -> {} e8s)", winner.winners[0], total_prize_pool, old_balance, user.balance);
-                }
-            });
+    let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
+    let balance_of = |account: Account| async move {
+        match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (account.clone(),)).await {
+            Ok((balance,)) => Some(balance.0.try_into().unwrap_or(u64::MAX)),
+            Err(e) => {
+                log_error("solvency", None, ErrorKind::LedgerCall, format!("icrc1_balance_of {:?} failed: {:?}", account, e));
+                None
+            }
         }
-
-        STATS.with(|s| {
-            let mut stats = s.borrow_mut();
-            stats.total_rounds += 1;
-            if !winner.participants.is_empty() {
-                // 统计总奖池
-                let total_prize_pool = winner.participants.len() as u64 * TICKET_PRICE;
-                stats.total_winnings += total_prize_pool;
+    };
+    
+    let treasury_balance = balance_of(treasury).await;
+    let mut failed_queries = treasury_balance.is_none() as u64;
+    let treasury_balance = treasury_balance.unwrap_or(0);
+    let mut deposit_balances: u64 = 0;
+    // 分批并发查询，调用数量有上限，不会因账户多而串行拖长对账
+    for batch in deposit_subaccounts.chunks(SOLVENCY_QUERY_BATCH) {
+        let accounts = batch.iter().map(|subaccount| Account { owner: ic_cdk::id(), subaccount: Some(subaccount.clone()) });
+        for balance in futures::future::join_all(accounts.map(balance_of)).await {
+            match balance {
+                Some(balance) => deposit_balances = deposit_balances.saturating_add(balance),
+                None => failed_queries += 1,
             }
-        });
-
-        // 记录历史中奖记录
-        if !winner.participants.is_empty() {
-            let total_prize_pool = winner.participants.len() as u64 * TICKET_PRICE;
-            HISTORICAL_WINNERS.with(|winners| {
-                let mut winners_ref = winners.borrow_mut();
-                winners_ref.push(HistoricalWinner {
-                    winner_principal: winner.winners[0].to_string(),
-                    amount: total_prize_pool,
-                    timestamp: time(),
-                    round_id: winner.id,
-                });
-                
-                // 只保留最近10次中奖记录
-                if winners_ref.len() > 10 {
-                    winners_ref.remove(0);
-                }
-            });
         }
-
-        // 创建新轮次
-        let mut new_round = Round {
-            id: winner.id + 1,
-            participants: vec![],
-            prize_pool: 0,
-            start_time: time(),
-            end_time: time() + ROUND_DURATION,
-            winners: vec![],
-        };
-        
-        // 随机选择假用户参与并让它们真实下注
-        let random_fakes = get_random_fake_users();
-        for fake_principal in random_fakes {
-            // 确保假用户已初始化
-            initialize_fake_users();
-            
-            // 让假用户真实下注（扣除余额）
-            USERS.with(|users| {
-                let mut users_ref = users.borrow_mut();
-                if let Some(user) = users_ref.get_mut(&fake_principal) {
-                    if user.balance >= TICKET_PRICE {
-                        let old_balance = user.balance;
-                        user.balance -= TICKET_PRICE;
-                        
-                        // 记录假用户下注交易
-                        let transaction = Transaction {
-                            amount: TICKET_PRICE,
-                            timestamp: time(),
-                            transaction_type: "Bet".to_string(),
-                            tx_hash: None,
-                            ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-                        };
-                        user.transaction_history.push(transaction);
-                        
-                        ic_cdk::println!("🤖 [FAKE_BET] Fake user {} placed bet: {} -> {} e8s", 
-                                       fake_principal, old_balance, user.balance);
-                    } else {
-                        ic_cdk::println!("❌ [FAKE_BET] Fake user {} insufficient balance: {} e8s", 
-                                       fake_principal, user.balance);
-                    }
+    }
+    
+    // 负债在所有链上查询完成后再统计，避免期间的状态变化
+    let liabilities = total_internal_value();
+    let assets = treasury_balance.saturating_add(deposit_balances);
+    let report = SolvencyReport {
+        timestamp: time(),
+        liabilities,
+        treasury_balance,
+        deposit_balances,
+        assets,
+        accounts_checked: deposit_subaccounts.len() as u64 + 1,
+        failed_queries,
+        solvent: assets >= liabilities,
+    };
+    
+    ic_cdk::println!("🧮 [SOLVENCY] Liabilities {} e8s, assets {} e8s ({} accounts, {} failed)", liabilities, assets, report.accounts_checked, failed_queries);
+    SOLVENCY.with(|s| {
+        let mut solvency = s.borrow_mut();
+        if failed_queries == 0 {
+            solvency.consecutive_incomplete = Some(0);
+            if solvency.paused == report.solvent {
+                solvency.paused = !report.solvent;
+                audit(if solvency.paused { "solvency_pause" } else { "solvency_resume" },
+                      format!("liabilities: {}, assets: {}", liabilities, assets), Ok(format!("{:?}", report)));
+                if solvency.paused {
+                    log_error("solvency", None, ErrorKind::Solvency, format!("Insolvent: liabilities {} e8s exceed on-chain assets {} e8s, betting and withdrawals paused", liabilities, assets));
+                } else {
+                    ic_cdk::println!("✅ [SOLVENCY] Solvent again, betting and withdrawals resumed");
                 }
-            });
-            
-            new_round.participants.push(fake_principal);
-            new_round.prize_pool += TICKET_PRICE;
+            }
+        } else {
+            // 连续多次无法完成对账时按不偿付处理，直到一次完整的对账确认偿付能力
+            let incomplete = solvency.consecutive_incomplete.unwrap_or(0) + 1;
+            solvency.consecutive_incomplete = Some(incomplete);
+            if incomplete >= MAX_INCOMPLETE_SOLVENCY_CHECKS && !solvency.paused {
+                solvency.paused = true;
+                audit("solvency_pause", format!("liabilities: {}, assets: {}", liabilities, assets),
+                      Err(format!("{} consecutive incomplete checks", incomplete)));
+                log_error("solvency", None, ErrorKind::Solvency, format!("{} consecutive solvency checks could not query every account, betting and withdrawals paused", incomplete));
+            }
         }
+        solvency.reports.insert(report.timestamp, report.clone());
+        while solvency.reports.len() > MAX_SOLVENCY_REPORTS {
+            solvency.reports.pop_first();
+        }
+    });
+    report
+}
 
-        CURRENT_ROUND.with(|r| {
-            *r.borrow_mut() = new_round.clone();
-        });
-        
-        ic_cdk::println!("🔄 [AUTO_DRAW] New round {} started: start_time={}, end_time={}", 
-                       new_round.id, new_round.start_time, new_round.end_time);
-    }
+fn solvency_paused() -> bool {
+    SOLVENCY.with(|s| s.borrow().paused)
 }
 
-fn assert_admin() {
-    let caller = ic_cdk::caller();
-    ADMIN.with(|a| {
-        if a.borrow().as_ref() != Some(&caller) {
-            ic_cdk::trap("Unauthorized");
+// 新增：根据奖金分配配置计算各档位比例（基点）
+fn prize_tier_shares(distribution: &PrizeDistribution) -> Vec<u64> {
+    match distribution {
+        PrizeDistribution::Tiered(shares) => shares.clone(),
+        PrizeDistribution::Equal(count) => {
+            let count = (*count).max(1) as u64;
+            let mut shares = vec![BPS_DENOMINATOR / count; count as usize];
+            // 除不尽的基点归头奖
+            shares[0] += BPS_DENOMINATOR % count;
+            shares
         }
-    });
+    }
 }
 
-#[update]
-pub fn initialize_fake_users_admin() {
-    assert_admin();
-    initialize_fake_users();
-    ic_cdk::println!("✅ [INIT_FAKE_USERS_ADMIN] Fake users initialized by admin");
+// 简单的线性同余生成器，用于从时间种子派生后续随机数
+fn next_random(rng: u64) -> u64 {
+    rng.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407)
 }
 
-#[query]
-pub fn get_fake_users_status() -> Vec<String> {
-    let mut status = Vec::new();
+// 新增：把彩票计入本轮账本：追加一段编号区间，并更新参与者汇总（二分查找定位）；
+// 返回该参与者在本轮持有的彩票数
+fn add_tickets(round: &mut Round, principal: Principal, is_bot: bool, count: u64) -> u64 {
+    round.total_tickets += count;
+    round.ticket_ranges.push(TicketRange { principal, is_bot, end: round.total_tickets });
     
-    USERS.with(|users| {
-        let users_ref = users.borrow();
-        
-        for fake_principal_str in FAKE_USERS.iter() {
-            if let Ok(fake_principal) = Principal::from_text(fake_principal_str) {
-                if let Some(user) = users_ref.get(&fake_principal) {
-                    status.push(format!("Fake User {}: Balance {} e8s ({} ckBTC)", 
-                        fake_principal, 
-                        user.balance, 
-                        user.balance as f64 / 100_000_000.0));
-                } else {
-                    status.push(format!("Fake User {}: Not initialized", fake_principal));
-                }
-            }
+    match round.participants.binary_search_by(|p| (p.principal, p.is_bot).cmp(&(principal, is_bot))) {
+        Ok(idx) => {
+            round.participants[idx].ticket_count += count;
+            round.participants[idx].ticket_count
+        },
+        Err(idx) => {
+            round.participants.insert(idx, Participant { principal, is_bot, ticket_count: count, win_probability: 0.0 });
+            count
         }
-    });
-    
-    status
+    }
 }
 
-#[update]
-pub fn recharge_fake_users() {
-    assert_admin();
+fn find_participant<'a>(round: &'a Round, principal: &Principal, is_bot: bool) -> Option<&'a Participant> {
+    round.participants
+        .binary_search_by(|p| (p.principal, p.is_bot).cmp(&(*principal, is_bot)))
+        .ok()
+        .map(|idx| &round.participants[idx])
+}
+
+// 新增：二分查找编号为 ticket 的彩票所在区间，O(log n)
+fn ticket_owner(ranges: &[TicketRange], ticket: u64) -> &TicketRange {
+    let idx = ranges.partition_point(|range| range.end <= ticket);
+    &ranges[idx.min(ranges.len() - 1)]
+}
+
+// 新增：无放回地抽取中奖者，每张彩票中奖概率相同，同一用户最多中一个档位。
+// 先在账本上直接抽签，抽到已中奖者时重抽；多次重抽仍失败时在剩余参与者中按彩票数加权抽取
+fn select_winners(round: &Round, count: usize, seed: u64) -> Vec<Participant> {
+    let mut rng = seed;
+    let mut winners: Vec<Participant> = Vec::new();
+    if round.total_tickets == 0 {
+        return winners;
+    }
     
-    USERS.with(|users| {
-        let mut users_ref = users.borrow_mut();
-        
-        for fake_principal_str in FAKE_USERS.iter() {
-            if let Ok(fake_principal) = Principal::from_text(fake_principal_str) {
-                if let Some(user) = users_ref.get_mut(&fake_principal) {
-                    let old_balance = user.balance;
-                    user.balance += 1000; // 给每个假用户充值1000 e8s
-                    
-                    // 记录充值交易
-                    let transaction = Transaction {
-                        amount: 1000,
-                        timestamp: time(),
-                        transaction_type: "FakeRecharge".to_string(),
-                        tx_hash: None,
-                        ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-                    };
-                    user.transaction_history.push(transaction);
-                    
-                    ic_cdk::println!("💰 [FAKE_RECHARGE] Fake user {} recharged: {} -> {} e8s", 
-                                   fake_principal, old_balance, user.balance);
-                }
+    let is_winner = |winners: &[Participant], principal: &Principal, is_bot: bool| {
+        winners.iter().any(|w| w.principal == *principal && w.is_bot == is_bot)
+    };
+    
+    while winners.len() < count.min(round.participants.len()) {
+        let mut selected = None;
+        for _ in 0..MAX_DRAW_ATTEMPTS {
+            let range = ticket_owner(&round.ticket_ranges, rng % round.total_tickets);
+            rng = next_random(rng);
+            if !is_winner(&winners, &range.principal, range.is_bot) {
+                selected = find_participant(round, &range.principal, range.is_bot).cloned();
+                break;
             }
         }
-    });
+        
+        let winner = selected.unwrap_or_else(|| {
+            let remaining: Vec<&Participant> = round.participants.iter()
+                .filter(|p| !is_winner(&winners, &p.principal, p.is_bot))
+                .collect();
+            let mut cumulative = Vec::with_capacity(remaining.len());
+            let mut total = 0;
+            for participant in remaining.iter() {
+                total += participant.ticket_count;
+                cumulative.push(total);
+            }
+            let ticket = rng % total.max(1);
+            rng = next_random(rng);
+            let idx = cumulative.partition_point(|end| *end <= ticket);
+            remaining[idx.min(remaining.len() - 1)].clone()
+        });
+        winners.push(winner);
+    }
     
-    save_to_stable_storage();
-    ic_cdk::println!("✅ [FAKE_RECHARGE] All fake users recharged");
+    winners
 }
 
-#[update]
-pub fn create_user(principal: String) {
-    // 确保定时器已初始化
-    ensure_timer_initialized();
+// 新增：按档位比例拆分奖池；未抽满的档位和取整余数都归头奖
+fn split_prize_pool(total_prize_pool: u64, shares: &[u64], winner_count: usize) -> Vec<u64> {
+    let mut amounts: Vec<u64> = shares.iter()
+        .take(winner_count)
+        .map(|share| (total_prize_pool as u128 * *share as u128 / BPS_DENOMINATOR as u128) as u64)
+        .collect();
     
-    let caller = ic_cdk::caller();
-    ic_cdk::println!("👤 [CREATE_USER] Creating user for caller: {}", caller);
-    ic_cdk::println!("👤 [CREATE_USER] Requested principal: {}", principal);
+    let distributed: u64 = amounts.iter().sum();
+    if let Some(first) = amounts.first_mut() {
+        *first += total_prize_pool - distributed;
+    }
     
-    let requested_principal = match Principal::from_text(&principal) {
-        Ok(p) => p,
-        Err(e) => {
-            ic_cdk::println!("❌ [CREATE_USER] Invalid principal format: {}", e);
-            ic_cdk::trap("Invalid principal format");
-        }
+    amounts
+}
+
+// 新增：把奖金或退款记入参与者账户。机器人记入机器人账户（已移除的机器人记入平台金库），
+// 机器人的中奖不计入用户统计
+fn credit_participant(participant: &Participant, amount: u64, round_id: u64, kind: TransactionKind) {
+    let rank = match kind {
+        TransactionKind::Win { rank, .. } => Some(rank),
+        TransactionKind::JackpotWin { .. } => Some(0),
+        _ => None,
+    };
+    let source = match kind {
+        TransactionKind::JackpotWin { .. } => LedgerAccount::Jackpot,
+        _ => LedgerAccount::RoundPool,
     };
+    if participant.is_bot {
+        let credited = BOTS.with(|bots| {
+            bots.borrow_mut().get_mut(&participant.principal).map(|bot| {
+                bot.balance += amount;
+                if rank.is_some() {
+                    bot.total_won += amount;
+                }
+            })
+        });
+        if credited.is_none() {
+            FEE_ACCOUNT.with(|f| {
+                f.borrow_mut().accrued += amount;
+            });
+            post(source, LedgerAccount::Fees, amount, format!("{} for removed bot {} in round {}", kind.name(), participant.principal, round_id));
+        } else {
+            post(source, LedgerAccount::Bot(participant.principal), amount, format!("{} in round {}", kind.name(), round_id));
+        }
+        ic_cdk::println!("🤖 [CREDIT] Bot {} credited {} e8s ({}) for round {}", participant.principal, amount, kind.name(), round_id);
+        return;
+    }
     
     USERS.with(|users| {
         let mut users_ref = users.borrow_mut();
-        
-        if !users_ref.contains_key(&requested_principal) {
-            // 为用户创建唯一的充值账户
-            let deposit_account = Account {
-                owner: requested_principal,
-                subaccount: Some(requested_principal.as_slice().to_vec()),
-            };
-            
-            ic_cdk::println!("🧪 [CREATE_USER] Creating user with deposit account: {:?}", deposit_account);
-            
-            users_ref.insert(requested_principal, User {
-                balance: 0,
-                transaction_history: vec![],
-                winning_history: vec![],
-                deposit_account,
-                principal_text: requested_principal.to_string(),
-                last_balance_check: time(),
+        if let Some(user) = users_ref.get_mut(&participant.principal) {
+            let old_balance = user.balance;
+            user.balance += amount;
+            let kind_name = kind.name().to_string();
+            post(source, participant_account(participant), amount, format!("{} in round {}", kind_name, round_id));
+            record_transaction(participant.principal, user, Transaction {
+                amount,
+                timestamp: time(),
+                kind,
+                tx_hash: None,
+                ckbtc_address: Some(format!("{:?}", user.deposit_account)),
             });
-            
-            STATS.with(|s| s.borrow_mut().active_users += 1);
-            ic_cdk::println!("✅ [CREATE_USER] User created successfully, active users: {}", 
-                           STATS.with(|s| s.borrow().active_users));
-        } else {
-            ic_cdk::println!("🧪 [CREATE_USER] User already exists: {}", requested_principal);
+            if rank.is_some() {
+                user.winning_history.push(Winning {
+                    amount,
+                    timestamp: time(),
+                    round_id,
+                    rank,
+                });
+            }
+            ic_cdk::println!("💰 [CREDIT] User {} credited {} e8s ({}) for round {} (balance: {} -> {} e8s)", participant.principal, amount, kind_name, round_id, old_balance, user.balance);
         }
     });
     
-    // 保存数据到稳定存储
-    save_to_stable_storage();
+    if rank.is_some() {
+        STATS.with(|s| {
+            s.borrow_mut().total_winnings += amount;
+        });
+    }
 }
 
-#[query]
-pub fn get_user_deposit_account(principal_str: String) -> Option<Account> {
-    let requested_principal = match Principal::from_text(&principal_str) {
-        Ok(p) => p,
-        Err(_) => return None,
-    };
+fn record_historical_winner(participant: &Participant, amount: u64, round_id: u64, rank: u32) {
+    HISTORICAL_WINNERS.with(|historical| {
+        historical.borrow_mut().push(HistoricalWinner {
+            winner_principal: participant.principal.to_string(),
+            amount,
+            timestamp: time(),
+            round_id,
+            rank: Some(rank),
+            is_bot: Some(participant.is_bot),
+        });
+    });
+}
+
+// 新增：抽取本轮各档位中奖者并派奖，记录中奖历史，返回按档位排序的中奖者
+fn distribute_prizes(round: &Round, total_prize_pool: u64, seed: u64) -> Vec<Participant> {
+    let shares = CONFIG.with(|c| prize_tier_shares(&c.borrow().prize_distribution));
+    let winners = select_winners(round, shares.len(), seed);
+    let amounts = split_prize_pool(total_prize_pool, &shares, winners.len());
     
-    USERS.with(|users| {
-        users.borrow().get(&requested_principal).map(|user| user.deposit_account.clone())
-    })
+    for (i, (winner, amount)) in winners.iter().zip(amounts.iter()).enumerate() {
+        let rank = i as u32 + 1;
+        ic_cdk::println!("🎉 [DRAW] Rank {} winner {} (bot: {}) wins {} e8s", rank, winner.principal, winner.is_bot, amount);
+        credit_participant(winner, *amount, round.id, TransactionKind::Win { round_id: round.id, rank });
+        record_historical_winner(winner, *amount, round.id, rank);
+    }
+    
+    ic_cdk::println!("🎲 [DRAW] Round {}: {} winners selected from {} participants", round.id, winners.len(), round.participants.len());
+    winners
 }
 
-// 新增：充值同步 - 只同步链上新增的余额到本地
-#[update]
-pub async fn update_balance_from_principal(principal_str: String) {
-    let principal = match Principal::from_text(&principal_str) {
-        Ok(p) => p,
-        Err(e) => {
-            ic_cdk::println!("❌ [UPDATE_BALANCE_FROM_PRINCIPAL] Invalid principal: {}", e);
-            return;
+// 新增：按配置的基点从奖池中抽取平台费，返回抽取的金额
+fn collect_house_fee(round_id: u64, total_prize_pool: u64) -> u64 {
+    let fee_bps = CONFIG.with(|c| c.borrow().house_fee_bps);
+    let fee = (total_prize_pool as u128 * fee_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+    
+    if fee > 0 {
+        FEE_ACCOUNT.with(|f| {
+            let mut fees = f.borrow_mut();
+            fees.accrued += fee;
+            fees.total_collected += fee;
+        });
+        post(LedgerAccount::RoundPool, LedgerAccount::Fees, fee, format!("house fee for round {}", round_id));
+        ic_cdk::println!("🏦 [HOUSE_FEE] Round {}: collected {} e8s fee ({} bps of {} e8s)", round_id, fee, fee_bps, total_prize_pool);
+    }
+    
+    fee
+}
+
+// 新增：按配置比例将奖池的一部分注入累积奖金，返回注入金额
+fn contribute_to_jackpot(round_id: u64, total_prize_pool: u64) -> u64 {
+    let (contribution_bps, odds) = CONFIG.with(|c| {
+        let config = c.borrow();
+        (config.jackpot_contribution_bps, config.jackpot_odds)
+    });
+    if odds == 0 {
+        return 0;
+    }
+    
+    let contribution = (total_prize_pool as u128 * contribution_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+    if contribution > 0 {
+        JACKPOT.with(|j| {
+            let mut jackpot = j.borrow_mut();
+            jackpot.balance += contribution;
+            jackpot.total_contributed += contribution;
+            ic_cdk::println!("💎 [JACKPOT] Round {}: contributed {} e8s, jackpot now {} e8s", round_id, contribution, jackpot.balance);
+        });
+        post(LedgerAccount::RoundPool, LedgerAccount::Jackpot, contribution, format!("jackpot contribution from round {}", round_id));
+    }
+    
+    contribution
+}
+
+// 新增：以 1/N 概率开出累积奖金给头奖得主，未开出则滚入下一轮；返回派发金额和抽签结果
+fn draw_jackpot(round_id: u64, winner: &Participant, seed: u64) -> (u64, Option<u64>) {
+    let odds = CONFIG.with(|c| c.borrow().jackpot_odds);
+    let balance = JACKPOT.with(|j| j.borrow().balance);
+    if odds == 0 || balance == 0 {
+        return (0, None);
+    }
+    
+    let roll = next_random(seed) % odds;
+    if roll != 0 {
+        ic_cdk::println!("💎 [JACKPOT] Round {}: not won (roll {} of {}), {} e8s rolls over", round_id, roll, odds, balance);
+        return (0, Some(roll));
+    }
+    
+    JACKPOT.with(|j| {
+        let mut jackpot = j.borrow_mut();
+        jackpot.balance = 0;
+        jackpot.total_paid += balance;
+        jackpot.last_won_round = Some(round_id);
+    });
+    
+    credit_participant(winner, balance, round_id, TransactionKind::JackpotWin { round_id });
+    record_historical_winner(winner, balance, round_id, 0);
+    
+    ic_cdk::println!("💎 [JACKPOT] Round {}: jackpot of {} e8s won by {}", round_id, balance, winner.principal);
+    (balance, Some(roll))
+}
+
+// 新增：统计本轮去重后的真实参与人数
+fn distinct_real_participants(round: &Round) -> usize {
+    // 参与者汇总中每个用户只有一条记录
+    round.participants.iter().filter(|p| !p.is_bot).count()
+}
+
+fn below_participant_threshold(round: &Round) -> bool {
+    let min_participants = CONFIG.with(|c| c.borrow().min_participants) as usize;
+    distinct_real_participants(round) < min_participants
+}
+
+// 新增：本轮彩票是否已达到每轮上限
+fn round_sold_out(round: &Round) -> bool {
+    let max_tickets_per_round = CONFIG.with(|c| c.borrow().max_tickets_per_round);
+    max_tickets_per_round > 0 && round.total_tickets >= max_tickets_per_round
+}
+
+// 新增：取消本轮并把每张彩票退回到参与者余额，返回退款总额
+fn refund_round(round: &Round) -> u64 {
+    let mut refunded = 0;
+    for participant in round.participants.iter() {
+        let amount = participant.ticket_count * TICKET_PRICE;
+        credit_participant(participant, amount, round.id, TransactionKind::Refund { round_id: round.id });
+        refunded += amount;
+        ic_cdk::println!("↩️ [REFUND] Round {}: refunded {} tickets ({} e8s) to {}", round.id, participant.ticket_count, amount, participant.principal);
+    }
+    
+    refunded
+}
+
+// 新增：记一笔复式账，从 from 转出 amount 记入 to
+fn post(from: LedgerAccount, to: LedgerAccount, amount: u64, memo: String) {
+    if amount == 0 {
+        return;
+    }
+    let timestamp = time();
+    LEDGER.with(|l| record_posting(&mut l.borrow_mut(), from, to, amount, memo, timestamp));
+}
+
+fn record_posting(ledger: &mut Ledger, from: LedgerAccount, to: LedgerAccount, amount: u64, memo: String, timestamp: u64) {
+    ledger.balances.entry(from.clone()).or_default().debited += amount;
+    ledger.balances.entry(to.clone()).or_default().credited += amount;
+    
+    let id = ledger.next_posting_id;
+    ledger.next_posting_id += 1;
+    ledger.journal.insert(id, Posting { id, timestamp, from, to, amount, memo });
+    if ledger.journal.len() > MAX_LEDGER_JOURNAL {
+        ledger.journal.pop_first();
+    }
+}
+
+fn ledger_balance(ledger: &Ledger, account: &LedgerAccount) -> i128 {
+    ledger.balances.get(account)
+        .map(|b| b.credited as i128 - b.debited as i128)
+        .unwrap_or(0)
+}
+
+// 新增：参与者对应的账本账户
+fn participant_account(participant: &Participant) -> LedgerAccount {
+    if participant.is_bot {
+        LedgerAccount::Bot(participant.principal)
+    } else {
+        LedgerAccount::User(participant.principal)
+    }
+}
+
+// 新增：按当前各账户余额开账，余额视为从 Treasury 转入
+fn open_ledger() {
+    let mut opening: Vec<(LedgerAccount, u64)> = Vec::new();
+    USERS.with(|users| {
+        opening.extend(users.borrow().iter().map(|(p, u)| (LedgerAccount::User(*p), u.balance)));
+    });
+    BOTS.with(|bots| {
+        opening.extend(bots.borrow().iter().map(|(p, b)| (LedgerAccount::Bot(*p), b.balance)));
+    });
+    opening.push((LedgerAccount::RoundPool, CURRENT_ROUND.with(|r| r.borrow().prize_pool)));
+    opening.push((LedgerAccount::Fees, FEE_ACCOUNT.with(|f| f.borrow().accrued)));
+    opening.push((LedgerAccount::Jackpot, JACKPOT.with(|j| j.borrow().balance)));
+    
+    for (account, amount) in opening {
+        post(LedgerAccount::Treasury, account, amount, "opening balance".to_string());
+    }
+    ic_cdk::println!("📒 [LEDGER] Opened ledger with {} e8s of internal value", total_internal_value());
+}
+
+// 新增：核对账本：借贷平衡、内部账户无负数，且每个账户的账本余额与实际余额一致
+fn check_ledger_invariants() -> LedgerReport {
+    let mut expected: BTreeMap<LedgerAccount, u64> = BTreeMap::new();
+    USERS.with(|users| {
+        expected.extend(users.borrow().iter().map(|(p, u)| (LedgerAccount::User(*p), u.balance)));
+    });
+    BOTS.with(|bots| {
+        expected.extend(bots.borrow().iter().map(|(p, b)| (LedgerAccount::Bot(*p), b.balance)));
+    });
+    expected.insert(LedgerAccount::RoundPool, CURRENT_ROUND.with(|r| r.borrow().prize_pool));
+    expected.insert(LedgerAccount::Fees, FEE_ACCOUNT.with(|f| f.borrow().accrued));
+    expected.insert(LedgerAccount::Jackpot, JACKPOT.with(|j| j.borrow().balance));
+    
+    LEDGER.with(|l| reconcile_ledger(&l.borrow(), &expected))
+}
+
+// 新增：用各账户的实际余额核对账本
+fn reconcile_ledger(ledger: &Ledger, expected: &BTreeMap<LedgerAccount, u64>) -> LedgerReport {
+    let mut violations = Vec::new();
+    
+    let credited: u128 = ledger.balances.values().map(|b| b.credited as u128).sum();
+    let debited: u128 = ledger.balances.values().map(|b| b.debited as u128).sum();
+    if credited != debited {
+        violations.push(format!("Ledger is unbalanced: {} credited, {} debited", credited, debited));
+    }
+    
+    for account in ledger.balances.keys() {
+        if *account != LedgerAccount::Treasury && !expected.contains_key(account) && ledger_balance(ledger, account) != 0 {
+            violations.push(format!("{:?} no longer exists but holds {} e8s in the ledger", account, ledger_balance(ledger, account)));
+        }
+    }
+    let mut internal_value: u64 = 0;
+    for (account, actual) in expected.iter() {
+        let booked = ledger_balance(ledger, account);
+        if booked < 0 {
+            violations.push(format!("{:?} has a negative ledger balance of {} e8s", account, booked));
+        }
+        if booked != *actual as i128 {
+            violations.push(format!("{:?} holds {} e8s but the ledger records {} e8s", account, actual, booked));
         }
+        internal_value += actual;
+    }
+    
+    LedgerReport {
+        postings: ledger.next_posting_id,
+        accounts: ledger.balances.len() as u64,
+        internal_value,
+        violations,
+    }
+}
+
+// 新增：系统内部总价值 = 用户余额 + 机器人余额 + 当前轮次奖池 + 未提取平台费 + 累积奖金
+fn total_internal_value() -> u64 {
+    let balances: u64 = USERS.with(|users| users.borrow().values().map(|user| user.balance).sum());
+    let bot_balances: u64 = BOTS.with(|bots| bots.borrow().values().map(|bot| bot.balance).sum());
+    let pool = CURRENT_ROUND.with(|r| r.borrow().prize_pool);
+    let fees = FEE_ACCOUNT.with(|f| f.borrow().accrued);
+    let jackpot = JACKPOT.with(|j| j.borrow().balance);
+    balances + bot_balances + pool + fees + jackpot
+}
+
+// 新增：开奖结算，自动开奖和管理员开奖共用。
+// 从本轮记录的 prize_pool 中派奖，结算前后内部总价值必须守恒，否则回滚本次调用
+fn settle_round(round: &Round, random_bytes: &[u8]) -> Settlement {
+    // 结算按轮次 id 幂等：已归档的轮次直接返回原结算结果
+    if let Some(archived) = ROUND_ARCHIVE.with(|archive| archive.borrow().get(&round.id).map(|a| a.settlement.clone())) {
+        ic_cdk::println!("⚠️ [SETTLE] Round {} is already settled", round.id);
+        return archived;
+    }
+    let value_before = total_internal_value();
+    let mut settlement = Settlement {
+        round_id: round.id,
+        prize_pool: round.prize_pool,
+        ..Default::default()
     };
+    let mut randomness = None;
+    
+    if round.participants.is_empty() {
+        ic_cdk::println!("🎲 [SETTLE] No participants in round {}", round.id);
+    } else if below_participant_threshold(round) {
+        settlement.refunded = refund_round(round);
+    } else {
+        settlement.house_fee = collect_house_fee(round.id, round.prize_pool);
+        settlement.jackpot_contribution = contribute_to_jackpot(round.id, round.prize_pool);
+        let prize = round.prize_pool - settlement.house_fee - settlement.jackpot_contribution;
+        let seed = random_u64(random_bytes, 0);
+        let jackpot_seed = random_u64(random_bytes, 8);
+        let winners = distribute_prizes(round, prize, seed);
+        let (jackpot_paid, jackpot_roll) = winners.first().map(|w| draw_jackpot(round.id, w, jackpot_seed)).unwrap_or((0, None));
+        settlement.prize_paid = prize;
+        settlement.jackpot_paid = jackpot_paid;
+        settlement.winners = winners.iter().map(|w| w.principal).collect();
+        randomness = Some(RandomnessProof {
+            seed,
+            total_tickets: round.total_tickets,
+            jackpot_odds: CONFIG.with(|c| c.borrow().jackpot_odds),
+            jackpot_roll,
+            jackpot_seed: Some(jackpot_seed),
+            random_bytes: Some(random_bytes.to_vec()),
+        });
+    }
+    
+    // 奖池已全部分配，清零避免重复计入
+    CURRENT_ROUND.with(|r| {
+        let mut current = r.borrow_mut();
+        current.winners = settlement.winners.clone();
+        current.prize_pool = 0;
+        current.transition(RoundStatus::Settled);
+    });
+    
+    STATS.with(|s| {
+        s.borrow_mut().total_rounds += 1;
+    });
+    
+    let value_after = total_internal_value();
+    if value_before != value_after {
+        let msg = format!("Value not conserved when settling round {}: {} e8s before, {} e8s after", round.id, value_before, value_after);
+        ic_cdk::trap(&msg);
+    }
+    
+    // 奖池的每一笔去向都应已记账
+    let pool_left = LEDGER.with(|l| ledger_balance(&l.borrow(), &LedgerAccount::RoundPool));
+    if pool_left != 0 {
+        let msg = format!("Round {} pool not fully posted: {} e8s left in the ledger", round.id, pool_left);
+        ic_cdk::trap(&msg);
+    }
+    
+    // 没有参与者的轮次没有资金变动，不归档也不写审计日志
+    if !round.participants.is_empty() {
+        archive_round(round, &settlement, randomness);
+        audit("settle_round", format!("round_id: {}", round.id), Ok(format!("{:?}", settlement)));
+    }
+    ic_cdk::println!("🧾 [SETTLE] {:?}", settlement);
+    settlement
+}
+
+// 新增：把已结算的轮次写入归档
+fn archive_round(round: &Round, settlement: &Settlement, randomness: Option<RandomnessProof>) {
+    let winners = HISTORICAL_WINNERS.with(|historical| {
+        let historical = historical.borrow();
+        let count = historical.iter().rev().take_while(|w| w.round_id == round.id).count();
+        historical[historical.len() - count..].to_vec()
+    });
+    let total_tickets = round.total_tickets.max(1) as f64;
+    let participants = round.participants.iter()
+        .map(|p| Participant { win_probability: p.ticket_count as f64 / total_tickets, ..p.clone() })
+        .collect();
+    
+    ROUND_ARCHIVE.with(|archive| {
+        archive.borrow_mut().insert(round.id, ArchivedRound {
+            id: round.id,
+            start_time: round.start_time,
+            end_time: round.end_time,
+            settled_at: time(),
+            total_tickets: round.total_tickets,
+            participants,
+            prize_pool: round.prize_pool,
+            winners,
+            randomness,
+            settlement: settlement.clone(),
+        });
+    });
+}
 
-    // 获取用户当前的本地余额
-    let current_local_balance = USERS.with(|users| {
-        users.borrow().get(&principal).map(|user| user.balance).unwrap_or(0)
+// 新增：开启下一轮，已注资的流动性机器人按配置用自己的余额购买彩票
+fn start_new_round(previous_round_id: u64) {
+    let mut new_round = Round {
+        id: previous_round_id + 1,
+        participants: vec![],
+        ticket_ranges: vec![],
+        total_tickets: 0,
+        prize_pool: 0,
+        start_time: time(),
+        end_time: next_draw_time(time()),
+        winners: vec![],
+        jackpot: None,
+        upcoming_draws: None,
+        status: Some(RoundStatus::Open),
+    };
+    
+    let max_tickets_per_round = CONFIG.with(|c| c.borrow().max_tickets_per_round);
+    let paused = solvency_paused();
+    BOTS.with(|bots| {
+        for bot in bots.borrow_mut().values_mut() {
+            let mut tickets = (bot.tickets_per_round as u64).min(bot.balance / TICKET_PRICE);
+            if max_tickets_per_round > 0 {
+                tickets = tickets.min(max_tickets_per_round.saturating_sub(new_round.total_tickets));
+            }
+            if tickets == 0 || paused {
+                continue;
+            }
+            
+            let stake = tickets * TICKET_PRICE;
+            bot.balance -= stake;
+            bot.total_bet += stake;
+            add_tickets(&mut new_round, bot.principal, true, tickets);
+            new_round.prize_pool += stake;
+            post(LedgerAccount::Bot(bot.principal), LedgerAccount::RoundPool, stake, format!("bet {} tickets in round {}", tickets, new_round.id));
+            
+            ic_cdk::println!("🤖 [BOT_BET] Bot {} bought {} tickets, balance left: {} e8s", bot.principal, tickets, bot.balance);
+        }
+    });
+    
+    ic_cdk::println!("🔄 [NEW_ROUND] Round {} started: start_time={}, end_time={}", 
+                   new_round.id, new_round.start_time, new_round.end_time);
+    CURRENT_ROUND.with(|r| {
+        *r.borrow_mut() = new_round;
     });
+    schedule_draw_timer();
+}
 
-    // 获取该用户的所有充值记录
-    let user_deposits = CKBTC_DEPOSITS.with(|deposits| {
-        deposits.borrow()
-            .values()
-            .filter(|deposit| deposit.principal == principal.to_string())
-            .cloned()
-            .collect::<Vec<CkBtcDeposit>>()
-    });
-
-    // 计算已确认的充值总额
-    let confirmed_deposits_total: u64 = user_deposits.iter()
-        .filter(|deposit| deposit.status == "confirmed")
-        .map(|deposit| deposit.amount)
-        .sum();
-
-    // 计算已记录到用户余额的充值总额（通过交易历史）
-    let recorded_deposits_total: u64 = USERS.with(|users| {
-        users.borrow().get(&principal).map(|user| {
-            user.transaction_history.iter()
-                .filter(|tx| tx.transaction_type == "CkBtcDeposit")
-                .map(|tx| tx.amount)
-                .sum()
-        }).unwrap_or(0)
-    });
-
-    ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] User: {}", principal);
-    ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] Current local balance: {} e8s", current_local_balance);
-    ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] Confirmed deposits total: {} e8s", confirmed_deposits_total);
-    ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] Recorded deposits total: {} e8s", recorded_deposits_total);
-
-    // 计算需要新增的充值金额
-    if confirmed_deposits_total > recorded_deposits_total {
-        let new_deposits = confirmed_deposits_total - recorded_deposits_total;
-        ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] New confirmed deposits: {} e8s", new_deposits);
-        
-        USERS.with(|users| {
-            let mut users_ref = users.borrow_mut();
-            if let Some(user) = users_ref.get_mut(&principal) {
-                // 只增加已确认但未记录的充值部分，保留游戏内的余额变动
-                user.balance += new_deposits;
-                
-                // 记录充值交易
-                user.transaction_history.push(Transaction {
-                    amount: new_deposits,
-                    timestamp: time(),
-                    transaction_type: "CkBtcDeposit".to_string(),
-                    tx_hash: None,
-                    ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-                });
-                
-                ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] Updated local balance: {} e8s", user.balance);
+// 新增：从 raw_rand 的随机字节中取出一个 u64
+fn random_u64(random_bytes: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    let available = random_bytes.len().saturating_sub(offset).min(8);
+    bytes[..available].copy_from_slice(&random_bytes[offset..offset + available]);
+    u64::from_le_bytes(bytes)
+}
+
+// 新增：关闭当前轮次并开奖。停止下注后才向管理 canister 请求 raw_rand，随机数在此之前不可预知；
+// 结算在对本 canister 的调用中完成，失败时该调用整体回滚，轮次回到 Closing 由看门狗重试。
+// 参与人数不足且配置为延长时返回 Ok(None)
+async fn draw_current_round(force: bool) -> Result<Option<Settlement>, String> {
+    let round = CURRENT_ROUND.with(|r| {
+        let mut round = r.borrow_mut();
+        match round.status() {
+            RoundStatus::Open if force || time() >= round.end_time => round.transition(RoundStatus::Closing),
+            RoundStatus::Closing => {}
+            status => return Err(format!("Round {} cannot be drawn while {:?}", round.id, status)),
+        }
+        Ok(round.clone())
+    })?;
+
+    if !round.participants.is_empty() && below_participant_threshold(&round) {
+        let action = CONFIG.with(|c| c.borrow().below_threshold_action.clone());
+        ic_cdk::println!("⏳ [AUTO_DRAW] Round {} has only {} distinct participants, applying {:?}", round.id, distinct_real_participants(&round), action);
+        // 已售完的轮次延长后也无法再下注，直接结算退款
+        let extend = matches!(action, ThresholdAction::Extend) && !round_sold_out(&round);
+        if !extend {
+            ic_cdk::println!("↩️ [AUTO_DRAW] Round {} will be refunded", round.id);
+        } else {
+            CURRENT_ROUND.with(|r| {
+                let mut round = r.borrow_mut();
+                round.transition(RoundStatus::Open);
+                round.end_time = next_draw_time(time());
+            });
+            refresh_certified_data();
+            schedule_draw_timer();
+            return Ok(None);
+        }
+    }
+
+    // 等待随机数期间状态已提交，认证的轮次状态也要同步
+    CURRENT_ROUND.with(|r| r.borrow_mut().transition(RoundStatus::Drawing));
+    refresh_certified_data();
+    let reopen = || {
+        CURRENT_ROUND.with(|r| {
+            let mut current = r.borrow_mut();
+            if current.id == round.id && current.status() == RoundStatus::Drawing {
+                current.transition(RoundStatus::Closing);
             }
         });
-        save_to_stable_storage();
-    } else {
-        ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] No new confirmed deposits found");
+        refresh_certified_data();
+    };
+    
+    let random_bytes = match raw_rand().await {
+        Ok((bytes,)) => bytes,
+        Err((code, msg)) => {
+            reopen();
+            let msg = format!("raw_rand failed for round {}: {:?} {}", round.id, code, msg);
+            log_error("draw", None, ErrorKind::ManagementCall, msg.clone());
+            return Err(msg);
+        }
+    };
+    
+    match ic_cdk::call::<_, (Settlement,)>(ic_cdk::id(), "settle_drawn_round", (round.id, random_bytes)).await {
+        Ok((settlement,)) => Ok(Some(settlement)),
+        Err((code, msg)) => {
+            // 结算调用已整体回滚（包括不变量检查失败时的 trap），在这里的已提交状态中记录错误
+            reopen();
+            let msg = format!("Settlement of round {} failed: {:?} {}", round.id, code, msg);
+            log_error("settle_round", None, ErrorKind::Invariant, msg.clone());
+            Err(msg)
+        }
+    }
+}
+
+// 开奖的结算步骤，只能由本 canister 在取得随机数后调用
+#[update(hidden = true)]
+fn settle_drawn_round(round_id: u64, random_bytes: Vec<u8>) -> Settlement {
+    if ic_cdk::caller() != ic_cdk::id() {
+        ic_cdk::trap("Unauthorized");
     }
+    let round = CURRENT_ROUND.with(|r| r.borrow().clone());
+    if round.id != round_id || round.status() != RoundStatus::Drawing {
+        ic_cdk::trap(&format!("Round {} is not being drawn", round_id));
+    }
+    
+    let settlement = settle_round(&round, &random_bytes);
+    start_new_round(round.id);
+    refresh_certified_data();
+    settlement
 }
 
+fn assert_admin() {
+    let caller = ic_cdk::caller();
+    ADMIN.with(|a| {
+        if a.borrow().as_ref() != Some(&caller) {
+            ic_cdk::trap("Unauthorized");
+        }
+    });
+}
 
 #[update]
-pub fn place_bet(principal_str: String) {
-    // 确保定时器已初始化
-    ensure_timer_initialized();
-    
+pub fn create_user(principal: String) {
     let caller = ic_cdk::caller();
-    ic_cdk::println!("🎲 [PLACE_BET] Caller: {}", caller);
-    ic_cdk::println!("🎲 [PLACE_BET] Requested principal: {}", principal_str);
+    ic_cdk::println!("👤 [CREATE_USER] Creating user for caller: {}", caller);
+    ic_cdk::println!("👤 [CREATE_USER] Requested principal: {}", principal);
     
-    let requested_principal = match Principal::from_text(&principal_str) {
+    let requested_principal = match Principal::from_text(&principal) {
         Ok(p) => p,
         Err(e) => {
-            ic_cdk::println!("❌ [PLACE_BET] Invalid principal format: {}", e);
+            ic_cdk::println!("❌ [CREATE_USER] Invalid principal format: {}", e);
             ic_cdk::trap("Invalid principal format");
         }
     };
     
-    ic_cdk::println!("🎲 [PLACE_BET] Starting bet placement for user: {}", requested_principal);
-    ic_cdk::println!("🎲 [PLACE_BET] Ticket price: {} e8s ({} ckBTC)", TICKET_PRICE, TICKET_PRICE as f64 / 100_000_000.0);
-
-    // 检查用户余额并扣除下注金额
     USERS.with(|users| {
         let mut users_ref = users.borrow_mut();
-        if let Some(user) = users_ref.get_mut(&requested_principal) {
-            ic_cdk::println!("🎲 [PLACE_BET] User balance before bet: {} e8s ({} ckBTC)", 
-                           user.balance, user.balance as f64 / 100_000_000.0);
-            ic_cdk::println!("🎲 [PLACE_BET] Required balance: {} e8s ({} ckBTC)", 
-                           TICKET_PRICE, TICKET_PRICE as f64 / 100_000_000.0);
-            
-            if user.balance < TICKET_PRICE {
-                ic_cdk::println!("❌ [PLACE_BET] INSUFFICIENT BALANCE: User has {} but needs {}", user.balance, TICKET_PRICE);
-                ic_cdk::trap("Insufficient balance for bet");
-            }
-            
-            // 扣除下注金额
-            let old_balance = user.balance;
-            user.balance -= TICKET_PRICE;
-            ic_cdk::println!("🎲 [PLACE_BET] Balance deducted: {} -> {} e8s", old_balance, user.balance);
+        
+        if let Entry::Vacant(entry) = users_ref.entry(requested_principal) {
+            // 为用户创建唯一的充值账户
+            let user = entry.insert(new_user(requested_principal));
+            ic_cdk::println!("🧪 [CREATE_USER] Created user with deposit account: {:?}", user.deposit_account);
             
-            // 记录下注交易
-            let transaction = Transaction {
-                amount: TICKET_PRICE,
-                timestamp: time(),
-                transaction_type: "Bet".to_string(),
-                tx_hash: None,
-                ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-            };
-            user.transaction_history.push(transaction);
-            ic_cdk::println!("🎲 [PLACE_BET] Transaction recorded: amount={}, type=Bet", TICKET_PRICE);
+            STATS.with(|s| s.borrow_mut().active_users += 1);
+            ic_cdk::println!("✅ [CREATE_USER] User created successfully, active users: {}", 
+                           STATS.with(|s| s.borrow().active_users));
         } else {
-            ic_cdk::println!("❌ [PLACE_BET] ERROR: User not found: {}", requested_principal);
-            ic_cdk::trap("User not found");
+            ic_cdk::println!("🧪 [CREATE_USER] User already exists: {}", requested_principal);
         }
     });
+    
+    refresh_certified_data();
+}
 
-    // 添加用户到当前轮次（支持多次下注）
-    CURRENT_ROUND.with(|r| {
-        let mut round = r.borrow_mut();
-        ic_cdk::println!("🎲 [PLACE_BET] Current round info: ID={}, participants={}, prize_pool={} e8s", 
-                       round.id, round.participants.len(), round.prize_pool);
-        
-        // 允许用户多次下注：每次下注都添加到参与者列表
-        round.participants.push(requested_principal);
-        let old_prize_pool = round.prize_pool;
-        round.prize_pool += TICKET_PRICE;
-        
-        // 计算用户在本轮的下注次数
-        let user_bet_count = round.participants.iter()
-            .filter(|&&p| p == requested_principal)
-            .count();
-        
-        ic_cdk::println!("🎲 [PLACE_BET] User added to round participants: {} (bet #{})", requested_principal, user_bet_count);
-        ic_cdk::println!("🎲 [PLACE_BET] Prize pool updated: {} -> {} e8s", old_prize_pool, round.prize_pool);
-        ic_cdk::println!("🎲 [PLACE_BET] Total participants in round: {}", round.participants.len());
+#[query]
+pub fn get_user_deposit_account(principal_str: String) -> Option<Account> {
+    let requested_principal = match Principal::from_text(&principal_str) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+    
+    USERS.with(|users| {
+        users.borrow().get(&requested_principal).map(|user| user.deposit_account.clone())
+    })
+}
+
+// 新增：充值同步 - 只同步链上新增的余额到本地；与定时同步共用充值账户的高水位，避免同一笔入账重复记入
+#[update]
+pub async fn update_balance_from_principal(principal_str: String) {
+    let principal = match Principal::from_text(&principal_str) {
+        Ok(p) => p,
+        Err(e) => {
+            ic_cdk::println!("❌ [UPDATE_BALANCE_FROM_PRINCIPAL] Invalid principal: {}", e);
+            return;
+        }
+    };
+    
+    match sync_user_deposits(principal).await {
+        Ok(0) => ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] No new deposits found for {}", principal),
+        Ok(credited) => ic_cdk::println!("💰 [UPDATE_BALANCE_FROM_PRINCIPAL] Credited {} e8s to {}", credited, principal),
+        Err(e) => ic_cdk::println!("❌ [UPDATE_BALANCE_FROM_PRINCIPAL] {}", e),
+    }
+}
+
+
+// 新增：推断旧交易发生时所在的轮次（当前轮次或已归档轮次）
+fn round_at(timestamp: u64) -> Option<u64> {
+    let current = CURRENT_ROUND.with(|r| {
+        let round = r.borrow();
+        (timestamp >= round.start_time).then_some(round.id)
     });
+    current.or_else(|| ROUND_ARCHIVE.with(|archive| {
+        archive.borrow().values()
+            .find(|round| round.start_time <= timestamp && timestamp < round.settled_at)
+            .map(|round| round.id)
+    }))
+}
 
-    // 更新统计
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        stats.total_bets += 1;
+// 新增：把旧版本字符串类型的交易迁移为 TransactionKind。
+// 中奖记录和中奖历史同时写入，时间戳相同；退款在轮次结算时写入
+fn migrate_transaction(legacy: LegacyTransaction, winning_history: &[Winning]) -> Transaction {
+    let winning = || winning_history.iter().find(|w| w.timestamp == legacy.timestamp && w.amount == legacy.amount);
+    let kind = match legacy.transaction_type.as_str() {
+        "Bet" => Some(TransactionKind::Bet {
+            round_id: round_at(legacy.timestamp),
+            tickets: legacy.quantity.unwrap_or(legacy.amount / TICKET_PRICE),
+        }),
+        "Win" => winning().map(|w| TransactionKind::Win { round_id: w.round_id, rank: w.rank.unwrap_or(1) }),
+        "JackpotWin" => winning().map(|w| TransactionKind::JackpotWin { round_id: w.round_id }),
+        "Refund" => ROUND_ARCHIVE.with(|archive| {
+            archive.borrow().values()
+                .find(|round| round.settled_at == legacy.timestamp)
+                .map(|round| TransactionKind::Refund { round_id: round.id })
+        }),
+        "CkBtcDeposit" => Some(TransactionKind::CkBtcDeposit { block_index: None }),
+        "Withdraw" => legacy.tx_hash.as_deref()
+            .and_then(|hash| hash.strip_prefix("withdraw_"))
+            .and_then(|index| index.parse().ok())
+            .map(|block_index| TransactionKind::Withdraw { block_index }),
+        "BalanceRecorded" => Some(TransactionKind::BalanceRecorded),
+        "MainAccountRecorded" => Some(TransactionKind::MainAccountRecorded),
+        "BalanceUpdate" => Some(TransactionKind::BalanceUpdate),
+        "FakeRecharge" => Some(TransactionKind::FakeRecharge),
+        _ => None,
+    };
+    
+    Transaction {
+        amount: legacy.amount,
+        timestamp: legacy.timestamp,
+        kind: kind.unwrap_or(TransactionKind::Legacy { transaction_type: legacy.transaction_type }),
+        tx_hash: legacy.tx_hash,
+        ckbtc_address: legacy.ckbtc_address,
+    }
+}
+
+// 新增：追加一条交易到全局交易日志并更新用户的汇总计数
+fn record_transaction(principal: Principal, user: &mut User, transaction: Transaction) {
+    match transaction.kind {
+        TransactionKind::CkBtcDeposit { .. } => user.total_deposited += transaction.amount,
+        TransactionKind::Bet { .. } => user.total_wagered += transaction.amount,
+        TransactionKind::Win { .. } | TransactionKind::JackpotWin { .. } => user.total_won += transaction.amount,
+        TransactionKind::Withdraw { .. } => user.total_withdrawn += transaction.amount,
+        _ => {}
+    }
+    let seq = user.transaction_count;
+    user.transaction_count += 1;
+    TRANSACTION_LOG.with(|log| {
+        log.borrow_mut().insert((principal, seq), transaction);
     });
+}
+
+// 新增：限额 b 是否比 a 更宽松（None 表示不限制）
+fn limit_is_looser(a: Option<u64>, b: Option<u64>) -> bool {
+    match (a, b) {
+        (Some(_), None) => true,
+        (Some(a), Some(b)) => b > a,
+        (None, _) => false,
+    }
+}
+
+// 新增：取两个限额中更严格的一个
+fn stricter_limit(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn period_limits_looser(current: &PeriodLimits, requested: &PeriodLimits) -> bool {
+    limit_is_looser(current.daily, requested.daily)
+        || limit_is_looser(current.weekly, requested.weekly)
+        || limit_is_looser(current.monthly, requested.monthly)
+}
+
+fn stricter_period_limits(current: &PeriodLimits, requested: &PeriodLimits) -> PeriodLimits {
+    PeriodLimits {
+        daily: stricter_limit(current.daily, requested.daily),
+        weekly: stricter_limit(current.weekly, requested.weekly),
+        monthly: stricter_limit(current.monthly, requested.monthly),
+    }
+}
+
+// 新增：到期后应用待生效的放宽限额
+fn apply_pending_limits(rg: &mut ResponsibleGambling, now: u64) {
+    if rg.pending_limits.is_some() && now >= rg.pending_effective_at {
+        rg.limits = rg.pending_limits.take().unwrap_or_default();
+        ic_cdk::println!("🛡️ [RESPONSIBLE_GAMBLING] Pending limit increase is now in effect");
+    }
+}
+
+// 新增：统计用户在 since 之后指定类型交易的金额之和
+fn transaction_total(principal: Principal, since: u64, matches_kind: impl Fn(&TransactionKind) -> bool) -> u64 {
+    TRANSACTION_LOG.with(|log| {
+        log.borrow()
+            .range((principal, 0)..=(principal, u64::MAX))
+            .rev()
+            .map(|(_, tx)| tx)
+            .take_while(|tx| tx.timestamp >= since)
+            .filter(|tx| matches_kind(&tx.kind))
+            .map(|tx| tx.amount)
+            .sum()
+    })
+}
+
+// 新增：按 24 小时 / 7 天 / 30 天窗口依次检查，返回 (限额, 窗口内已用额度) 中剩余最少的一项
+fn tightest_period(limits: &PeriodLimits, now: u64, used_since: impl Fn(u64) -> u64) -> Option<(u64, u64)> {
+    [(limits.daily, 1), (limits.weekly, 7), (limits.monthly, 30)]
+        .into_iter()
+        .filter_map(|(limit, days)| limit.map(|limit| (limit, used_since(now.saturating_sub(days * NANOS_PER_DAY)))))
+        .min_by_key(|(limit, used)| limit.saturating_sub(*used))
+}
+
+fn net_loss_since(principal: Principal, since: u64) -> u64 {
+    transaction_total(principal, since, |kind| matches!(kind, TransactionKind::Bet { .. }))
+        .saturating_sub(transaction_total(principal, since, |kind| matches!(kind,
+            TransactionKind::Win { .. } | TransactionKind::JackpotWin { .. } | TransactionKind::Refund { .. })))
+}
+
+// 新增：检查理性博彩限制（自我排除、会话冷却、亏损上限），通过后记录本次下注时间
+fn check_responsible_gambling(principal: Principal, user: &mut User, cost: u64, now: u64) -> Result<(), BetError> {
+    let mut rg = match user.responsible_gambling.take() {
+        Some(rg) => rg,
+        None => return Ok(()),
+    };
+    apply_pending_limits(&mut rg, now);
+    let result = (|| {
+        if let Some(until) = rg.self_excluded_until {
+            if now < until {
+                return Err(BetError::SelfExcluded { until });
+            }
+        }
+        
+        let cooldown = rg.limits.session_cooldown_secs.saturating_mul(NANOS_PER_SECOND);
+        let session_continues = rg.last_bet_at > 0 && now.saturating_sub(rg.last_bet_at) < cooldown;
+        if let Some(duration) = rg.limits.session_duration_secs {
+            if session_continues && now.saturating_sub(rg.session_started_at) >= duration.saturating_mul(NANOS_PER_SECOND) {
+                return Err(BetError::SessionCooldown { until: rg.last_bet_at.saturating_add(cooldown) });
+            }
+        }
+        
+        if let Some((limit, lost)) = tightest_period(&rg.limits.loss, now, |since| net_loss_since(principal, since)) {
+            if lost.saturating_add(cost) > limit {
+                return Err(BetError::LossLimitExceeded { limit, lost });
+            }
+        }
+        
+        if !session_continues {
+            rg.session_started_at = now;
+        }
+        rg.last_bet_at = now;
+        Ok(())
+    })();
+    user.responsible_gambling = Some(rg);
+    result
+}
+
+// 新增：用户当前还能入账的充值额度；自我排除期间为 0，无限额时为 u64::MAX
+fn deposit_allowance(principal: Principal, user: &mut User, now: u64) -> u64 {
+    let Some(rg) = user.responsible_gambling.as_mut() else {
+        return u64::MAX;
+    };
+    apply_pending_limits(rg, now);
+    if rg.self_excluded_until.is_some_and(|until| now < until) {
+        return 0;
+    }
+    let limits = rg.limits.deposit.clone();
+    tightest_period(&limits, now, |since| transaction_total(principal, since, |kind| matches!(kind, TransactionKind::CkBtcDeposit { .. })))
+        .map(|(limit, deposited)| limit.saturating_sub(deposited))
+        .unwrap_or(u64::MAX)
+}
+
+// 新增：为用户一次购买多张彩票。原子地扣除 count * TICKET_PRICE 并记录一条带数量的下注交易，
+// 返回用户在本轮持有的彩票总数
+fn purchase_tickets(principal: Principal, count: u64) -> Result<u64, BetError> {
+    if count == 0 {
+        return Err(BetError::InvalidTicketCount);
+    }
+    if solvency_paused() {
+        return Err(BetError::BettingPaused);
+    }
+    let cost = count.checked_mul(TICKET_PRICE).ok_or(BetError::InvalidTicketCount)?;
+    let config = CONFIG.with(|c| c.borrow().clone());
+    let today = time() / NANOS_PER_DAY;
+    
+    ic_cdk::println!("🎲 [BUY_TICKETS] User {} buying {} tickets at {} e8s each ({} e8s total)", principal, count, TICKET_PRICE, cost);
+    
+    // 检查本轮的彩票上限
+    let round_id = CURRENT_ROUND.with(|r| {
+        let round = r.borrow();
+        if round.status() != RoundStatus::Open || time() >= round.end_time {
+            return Err(BetError::RoundClosed { round_id: round.id });
+        }
+        if config.max_tickets_per_user_per_round > 0 {
+            let held = find_participant(&round, &principal, false).map(|p| p.ticket_count).unwrap_or(0);
+            if held.checked_add(count).is_none_or(|total| total > config.max_tickets_per_user_per_round) {
+                return Err(BetError::UserRoundLimitExceeded { limit: config.max_tickets_per_user_per_round, held });
+            }
+        }
+        let total_tickets = round.total_tickets.checked_add(count).ok_or(BetError::InvalidTicketCount)?;
+        if config.max_tickets_per_round > 0 && total_tickets > config.max_tickets_per_round {
+            return Err(BetError::RoundFull {
+                limit: config.max_tickets_per_round,
+                remaining: config.max_tickets_per_round.saturating_sub(round.total_tickets),
+            });
+        }
+        Ok(round.id)
+    })?;
+
+    // 检查用户余额和每日限额并扣除下注金额
+    USERS.with(|users| {
+        let mut users_ref = users.borrow_mut();
+        let user = match users_ref.get_mut(&principal) {
+            Some(user) => user,
+            None => {
+                ic_cdk::println!("❌ [BUY_TICKETS] ERROR: User not found: {}", principal);
+                return Err(BetError::UserNotFound);
+            }
+        };
+        
+        if user.balance < cost {
+            ic_cdk::println!("❌ [BUY_TICKETS] INSUFFICIENT BALANCE: User has {} but needs {}", user.balance, cost);
+            return Err(BetError::InsufficientBalance { balance: user.balance, required: cost });
+        }
+        
+        let staked_today = user.daily_stake.as_ref()
+            .filter(|stake| stake.day == today)
+            .map(|stake| stake.amount)
+            .unwrap_or(0);
+        if config.max_daily_stake_per_user > 0 && staked_today + cost > config.max_daily_stake_per_user {
+            return Err(BetError::DailyStakeLimitExceeded { limit: config.max_daily_stake_per_user, staked_today });
+        }
+        
+        if let Err(e) = check_responsible_gambling(principal, user, cost, time()) {
+            ic_cdk::println!("🛡️ [BUY_TICKETS] Bet blocked by responsible gambling limits: {}", e);
+            return Err(e);
+        }
+        
+        // 扣除下注金额
+        let old_balance = user.balance;
+        user.balance -= cost;
+        post(LedgerAccount::User(principal), LedgerAccount::RoundPool, cost, format!("bet {} tickets in round {}", count, round_id));
+        user.daily_stake = Some(DailyStake { day: today, amount: staked_today + cost });
+        ic_cdk::println!("🎲 [BUY_TICKETS] Balance deducted: {} -> {} e8s", old_balance, user.balance);
+        
+        // 记录下注交易
+        record_transaction(principal, user, Transaction {
+            amount: cost,
+            timestamp: time(),
+            kind: TransactionKind::Bet { round_id: Some(round_id), tickets: count },
+            tx_hash: None,
+            ckbtc_address: Some(format!("{:?}", user.deposit_account)),
+        });
+        Ok(())
+    })?;
+
+    // 添加用户到当前轮次（支持多次下注，同一用户只占一条参与记录）
+    let (user_ticket_count, round_full) = CURRENT_ROUND.with(|r| {
+        let mut round = r.borrow_mut();
+        let user_ticket_count = add_tickets(&mut round, principal, false, count);
+        let old_prize_pool = round.prize_pool;
+        round.prize_pool += cost;
+        
+        ic_cdk::println!("🎲 [BUY_TICKETS] Round {}: user {} now holds {} tickets", round.id, principal, user_ticket_count);
+        ic_cdk::println!("🎲 [BUY_TICKETS] Prize pool updated: {} -> {} e8s", old_prize_pool, round.prize_pool);
+        (user_ticket_count, round_sold_out(&round))
+    });
+
+    // 更新统计
+    STATS.with(|s| {
+        s.borrow_mut().total_bets += count;
+    });
+    
+    // 本轮彩票已售完：停止下注，由定时器在另一条消息中开奖，购买者无法影响随机数
+    if round_full {
+        ic_cdk::println!("🎯 [BUY_TICKETS] Round ticket limit of {} reached, drawing early", config.max_tickets_per_round);
+        CURRENT_ROUND.with(|r| {
+            let mut round = r.borrow_mut();
+            round.transition(RoundStatus::Closing);
+            round.end_time = time(); // 定时器按 end_time 触发，提前截止后立即开奖
+        });
+        schedule_draw_timer();
+    }
+
+    Ok(user_ticket_count)
+}
+
+#[update]
+pub fn place_bet(principal_str: String) {
+    let caller = ic_cdk::caller();
+    ic_cdk::println!("🎲 [PLACE_BET] Caller: {}", caller);
+    ic_cdk::println!("🎲 [PLACE_BET] Requested principal: {}", principal_str);
+    
+    let requested_principal = match Principal::from_text(&principal_str) {
+        Ok(p) => p,
+        Err(e) => {
+            ic_cdk::println!("❌ [PLACE_BET] Invalid principal format: {}", e);
+            ic_cdk::trap("Invalid principal format");
+        }
+    };
+    
+    if let Err(e) = purchase_tickets(requested_principal, 1) {
+        ic_cdk::println!("❌ [PLACE_BET] Bet rejected: {:?}", e);
+        ic_cdk::trap(&e.to_string());
+    }
+
+    ic_cdk::println!("✅ [PLACE_BET] Bet placement successful for user: {}", requested_principal);
+    
+    refresh_certified_data();
+}
+
+/// Buy several tickets for the caller in the current round with a single balance deduction
+#[update]
+pub fn buy_tickets(count: u64) -> Result<u64, BetError> {
+    let caller = ic_cdk::caller();
+    let tickets = purchase_tickets(caller, count)?;
+    
+    ic_cdk::println!("✅ [BUY_TICKETS] {} tickets bought by {}, holding {} in this round", count, caller, tickets);
+    
+    refresh_certified_data();
+    Ok(tickets)
+}
+
+/// Set the caller's deposit, loss and session limits. Stricter limits apply immediately;
+/// looser ones take effect after a 24 hour delay
+#[update]
+pub fn set_gambling_limits(limits: GamblingLimits) -> Result<ResponsibleGambling, String> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    // 没有休息时间时任何间隔都会开始新会话，时长上限永远不会触发
+    if limits.session_duration_secs.is_some() && limits.session_cooldown_secs == 0 {
+        return Err("A session duration limit requires a non-zero session cooldown".to_string());
+    }
+    
+    USERS.with(|users| {
+        let mut users_ref = users.borrow_mut();
+        let user = users_ref.get_mut(&caller).ok_or("User not found")?;
+        let rg = user.responsible_gambling.get_or_insert_with(ResponsibleGambling::default);
+        apply_pending_limits(rg, now);
+        
+        let current = &rg.limits;
+        let loosens = period_limits_looser(&current.deposit, &limits.deposit)
+            || period_limits_looser(&current.loss, &limits.loss)
+            || limit_is_looser(current.session_duration_secs, limits.session_duration_secs)
+            || limits.session_cooldown_secs < current.session_cooldown_secs;
+        
+        // 收紧的部分立即生效，放宽的部分进入冷静期
+        let tightened = GamblingLimits {
+            deposit: stricter_period_limits(&current.deposit, &limits.deposit),
+            loss: stricter_period_limits(&current.loss, &limits.loss),
+            session_duration_secs: stricter_limit(current.session_duration_secs, limits.session_duration_secs),
+            session_cooldown_secs: current.session_cooldown_secs.max(limits.session_cooldown_secs),
+        };
+        rg.limits = tightened;
+        if loosens {
+            rg.pending_limits = Some(limits);
+            rg.pending_effective_at = now + LIMIT_INCREASE_DELAY;
+            ic_cdk::println!("🛡️ [SET_GAMBLING_LIMITS] {} requested a limit increase, effective at {}", caller, rg.pending_effective_at);
+        } else {
+            rg.pending_limits = None;
+            ic_cdk::println!("🛡️ [SET_GAMBLING_LIMITS] {} updated limits: {:?}", caller, rg.limits);
+        }
+        Ok(rg.clone())
+    })
+}
+
+/// Self-exclude the caller from depositing and betting for the given number of days,
+/// or permanently when no duration is given. An exclusion can be extended but never shortened
+#[update]
+pub fn self_exclude(days: Option<u64>) -> Result<ResponsibleGambling, String> {
+    let caller = ic_cdk::caller();
+    let until = match days {
+        Some(0) => return Err("Exclusion period must be at least one day".to_string()),
+        Some(days) => time().saturating_add(days.saturating_mul(NANOS_PER_DAY)),
+        None => u64::MAX,
+    };
+    
+    USERS.with(|users| {
+        let mut users_ref = users.borrow_mut();
+        let user = users_ref.get_mut(&caller).ok_or("User not found")?;
+        let rg = user.responsible_gambling.get_or_insert_with(ResponsibleGambling::default);
+        let until = rg.self_excluded_until.map_or(until, |current| current.max(until));
+        rg.self_excluded_until = Some(until);
+        ic_cdk::println!("🛡️ [SELF_EXCLUDE] {} is self-excluded until {}", caller, until);
+        Ok(rg.clone())
+    })
+}
+
+/// Get the caller's responsible gambling limits and exclusion status
+#[query]
+pub fn get_gambling_limits() -> Option<ResponsibleGambling> {
+    let caller = ic_cdk::caller();
+    USERS.with(|users| {
+        users.borrow().get(&caller).and_then(|user| user.responsible_gambling.clone())
+    })
+}
+
+#[update]
+pub async fn withdraw_balance(principal_str: String, amount: u64) -> Result<String, String> {
+    let arguments = format!("principal: {}, amount: {}", principal_str, amount);
+    let result = execute_withdrawal(principal_str, amount).await;
+    audit("withdraw_balance", arguments, result.clone());
+    result
+}
+
+async fn execute_withdrawal(principal_str: String, amount: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    ic_cdk::println!("💸 [WITHDRAW] Withdrawal requested by: {}", caller);
+    ic_cdk::println!("💸 [WITHDRAW] Requested principal: {}", principal_str);
+    ic_cdk::println!("💸 [WITHDRAW] Amount: {} e8s", amount);
+    
+    if solvency_paused() {
+        return Err("Withdrawals are paused until the platform is solvent again".to_string());
+    }
+    
+    let requested_principal = match Principal::from_text(&principal_str) {
+        Ok(p) => p,
+        Err(e) => {
+            ic_cdk::println!("❌ [WITHDRAW] Invalid principal format: {}", e);
+            return Err(format!("Invalid principal format: {}", e));
+        }
+    };
+    
+    // 检查用户余额
+    let user_info = USERS.with(|users| {
+        users.borrow().get(&requested_principal).cloned()
+    });
+    
+    if let Some(user) = user_info {
+        if user.balance < amount {
+            ic_cdk::println!("❌ [WITHDRAW] INSUFFICIENT BALANCE: User has {} but wants to withdraw {}", user.balance, amount);
+            return Err(format!("Insufficient balance for withdrawal. User has {} but wants to withdraw {}", user.balance, amount));
+        }
+        
+        // 检查用户的 deposit account 是否有足够的链上余额
+        let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
+        let deposit_account_balance = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (user.deposit_account.clone(),)).await {
+            Ok((balance,)) => balance.0.try_into().unwrap_or(0),
+            Err(error) => {
+                log_error("withdraw", Some(requested_principal), ErrorKind::LedgerCall, format!("Failed to get deposit account balance: {:?}", error));
+                0
+            }
+        };
+        
+        if deposit_account_balance < amount {
+            ic_cdk::println!("❌ [WITHDRAW] INSUFFICIENT CHAIN BALANCE: Deposit account has {} but needs {}", deposit_account_balance, amount);
+            return Err(format!("Insufficient chain balance. Deposit account has {} but needs {}. Please wait for deposits to confirm.", deposit_account_balance, amount));
+        }
+        
+        // 记录提现前的余额信息
+        ic_cdk::println!("💰 [WITHDRAW] User local balance: {} e8s", user.balance);
+        ic_cdk::println!("💰 [WITHDRAW] User deposit account balance: {} e8s", deposit_account_balance);
+        
+        // 从用户的 deposit account 提现给用户
+        
+        // 创建转账参数 - 从用户的 deposit account 转给用户
+        let transfer_args = TransferArgs {
+            to: Account {
+                owner: requested_principal,
+                subaccount: None,
+            },
+            amount,
+            fee: Some(1_000), // 0.00001 ckBTC fee
+            memo: Some("Lottery withdrawal from user deposit account".as_bytes().to_vec()),
+            from_subaccount: user.deposit_account.subaccount.clone(), // ✅ 从用户的 deposit account 转账
+            created_at_time: Some(time()),
+        };
+        
+        // 先扣减余额再转账，转账失败时退回；查询链上余额期间余额可能已被下注花掉，这里重新检查
+        USERS.with(|users| {
+            let mut users_ref = users.borrow_mut();
+            let user = users_ref.get_mut(&requested_principal).ok_or_else(|| "User not found".to_string())?;
+            if user.balance < amount {
+                return Err(format!("Insufficient balance for withdrawal. User has {} but wants to withdraw {}", user.balance, amount));
+            }
+            user.balance -= amount;
+            post(LedgerAccount::User(requested_principal), LedgerAccount::Treasury, amount, "withdrawal".to_string());
+            Ok(())
+        })?;
+        
+            // 转账在途期间计入排队中的提现，并暂停同步该充值账户
+            WITHDRAWALS_IN_FLIGHT.with(|w| {
+                let mut w = w.borrow_mut();
+                w.0 += 1;
+                w.1 += amount;
+            });
+            begin_deposit_outflow(requested_principal);
+            let transfer_result = ic_cdk::call::<_, (TransferResult,)>(ckbtc_canister, "icrc1_transfer", (transfer_args,)).await;
+            WITHDRAWALS_IN_FLIGHT.with(|w| {
+                let mut w = w.borrow_mut();
+                w.0 -= 1;
+                w.1 -= amount;
+            });
+            // 成功时先记录转出再恢复同步，避免同步把这笔转出误判为缺口
+            if let Ok((TransferResult::Ok(_),)) = &transfer_result {
+                record_deposit_outflow(requested_principal, &user.deposit_account, amount + CKBTC_TRANSFER_FEE);
+            }
+            end_deposit_outflow(requested_principal);
+            
+            if !matches!(transfer_result, Ok((TransferResult::Ok(_),))) {
+                USERS.with(|users| {
+                    if let Some(user) = users.borrow_mut().get_mut(&requested_principal) {
+                        user.balance += amount;
+                    }
+                });
+                post(LedgerAccount::Treasury, LedgerAccount::User(requested_principal), amount, "failed withdrawal reversed".to_string());
+            }
+            
+            match transfer_result {
+                Ok((result,)) => {
+                    match result {
+                        TransferResult::Ok(block_index) => {
+                        ic_cdk::println!("✅ [WITHDRAW] Withdrawal from treasury successful! Block index: {}", block_index);
+                            
+                            USERS.with(|users| {
+                                let mut users_ref = users.borrow_mut();
+                                if let Some(user) = users_ref.get_mut(&requested_principal) {
+                                    // 记录提现交易
+                                    record_transaction(requested_principal, user, Transaction {
+                                        amount,
+                                        timestamp: time(),
+                                        kind: TransactionKind::Withdraw { block_index },
+                                        tx_hash: Some(format!("withdraw_{}", block_index)),
+                                    ckbtc_address: Some(format!("User Account: {}", requested_principal)),
+                                    });
+                                }
+                            });
+                        
+                        Ok(format!("Withdrawal successful! Block index: {}", block_index))
+                        },
+                        TransferResult::Err(error) => {
+                        log_error("withdraw", Some(requested_principal), ErrorKind::LedgerRejected, format!("Transfer of {} e8s from treasury failed: {:?}", amount, error));
+                        Err(format!("Transfer from treasury failed: {:?}", error))
+                        }
+                    }
+                },
+                Err(error) => {
+                    log_error("withdraw", Some(requested_principal), ErrorKind::LedgerCall, format!("Transfer of {} e8s: call to ckBTC canister failed: {:?}", amount, error));
+                Err(format!("Call to ckBTC canister failed: {:?}", error))
+                }
+            }
+    } else {
+        Err("User not found".to_string())
+    }
+}
+
+// 新增：按时间倒序分页读取用户的交易记录，start 为跳过的匹配条数
+fn transaction_page(principal: Principal, start: u64, limit: u64, filter_type: Option<String>) -> TransactionPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    
+    TRANSACTION_LOG.with(|log| {
+        let log_ref = log.borrow();
+        let matching = || {
+            log_ref.range((principal, 0)..=(principal, u64::MAX))
+                .rev()
+                .filter(|(_, tx)| filter_type.as_ref().is_none_or(|t| tx.kind.name() == t))
+        };
+        TransactionPage {
+            entries: matching()
+                .skip(start as usize)
+                .take(limit)
+                .map(|((_, seq), tx)| TransactionEntry { seq: *seq, transaction: tx.clone() })
+                .collect(),
+            total: matching().count() as u64,
+        }
+    })
+}
+
+/// Get the caller's transactions, newest first, optionally filtered by transaction type
+#[query]
+pub fn get_my_transactions(start: u64, limit: u64, filter_type: Option<String>) -> TransactionPage {
+    transaction_page(ic_cdk::caller(), start, limit, filter_type)
+}
+
+/// Get a user's transactions, newest first, optionally filtered by transaction type
+#[query]
+pub fn get_user_transactions(principal: Principal, start: u64, limit: u64, filter_type: Option<String>) -> TransactionPage {
+    transaction_page(principal, start, limit, filter_type)
+}
+
+/// Get a user; responsible gambling limits are only included when the caller looks up their own account
+#[query]
+pub fn get_user(principal: Principal) -> Option<User> {
+    ic_cdk::println!("🔍 [GET_USER] Looking up user: {}", principal);
+    let mut result = USERS.with(|users| users.borrow().get(&principal).cloned());
+    // 理性博彩限制和自我排除状态属于隐私，不向其他调用者公开
+    if ic_cdk::caller() != principal {
+        if let Some(user) = result.as_mut() {
+            user.responsible_gambling = None;
+        }
+    }
+    if let Some(ref user) = result {
+        ic_cdk::println!("✅ [GET_USER] User found: {}", principal);
+        ic_cdk::println!("✅ [GET_USER] User balance: {} e8s ({} ckBTC)", 
+                       user.balance, user.balance as f64 / 100_000_000.0);
+    } else {
+        ic_cdk::println!("❌ [GET_USER] User not found: {}", principal);
+    }
+    result
+}
+
+#[update]
+pub async fn trigger_draw() {
+    assert_admin();
+
+    let round = CURRENT_ROUND.with(|r| r.borrow().clone());
+    if round.participants.is_empty() {
+        ic_cdk::trap("No participants");
+    }
+    if below_participant_threshold(&round) {
+        ic_cdk::trap("Not enough distinct participants");
+    }
+
+    let result = draw_current_round(true).await;
+    audit("trigger_draw", format!("round_id: {}", round.id), result.as_ref()
+        .map(|settlement| format!("winners: {:?}", settlement.as_ref().map(|s| &s.winners)))
+        .map_err(|e| e.clone()));
+    // 等待随机数后不能再 trap，否则会回滚回到 Closing 的状态
+    if let Err(e) = result {
+        ic_cdk::println!("❌ [TRIGGER_DRAW] {}", e);
+    }
+}
+
+/// Configure how each round's prize pool is split between winners (admin function)
+#[update]
+pub fn set_prize_distribution(distribution: PrizeDistribution) -> Result<(), String> {
+    assert_admin();
+    
+    audited("set_prize_distribution", format!("distribution: {:?}", distribution), || {
+        match &distribution {
+            PrizeDistribution::Tiered(shares) => {
+                if shares.is_empty() || shares.len() > MAX_PRIZE_TIERS {
+                    return Err(format!("Prize tiers must contain between 1 and {} entries", MAX_PRIZE_TIERS));
+                }
+                if shares.iter().sum::<u64>() != BPS_DENOMINATOR {
+                    return Err(format!("Prize tier shares must add up to {} basis points", BPS_DENOMINATOR));
+                }
+            },
+            PrizeDistribution::Equal(count) => {
+                if *count == 0 || *count as usize > MAX_PRIZE_TIERS {
+                    return Err(format!("Number of winners must be between 1 and {}", MAX_PRIZE_TIERS));
+                }
+            }
+        }
+        
+        ic_cdk::println!("⚙️ [SET_PRIZE_DISTRIBUTION] New prize distribution: {:?}", distribution);
+        CONFIG.with(|c| {
+            c.borrow_mut().prize_distribution = distribution;
+        });
+        Ok(())
+    })
+}
+
+/// Configure the progressive jackpot: share of each pool added to it and the 1-in-N payout odds (admin function)
+#[update]
+pub fn set_jackpot_config(contribution_bps: u64, odds: u64) -> Result<(), String> {
+    assert_admin();
+    
+    audited("set_jackpot_config", format!("contribution_bps: {}, odds: {}", contribution_bps, odds), || {
+        if contribution_bps > MAX_JACKPOT_CONTRIBUTION_BPS {
+            return Err(format!("Jackpot contribution cannot exceed {} basis points", MAX_JACKPOT_CONTRIBUTION_BPS));
+        }
+        // odds 为 0 时累积奖金永远不会开出，继续抽成会让资金只进不出
+        if contribution_bps > 0 && odds == 0 {
+            return Err("Jackpot odds must be non-zero while contributions are enabled".to_string());
+        }
+        
+        ic_cdk::println!("⚙️ [SET_JACKPOT_CONFIG] Jackpot contribution {} bps, odds 1 in {}", contribution_bps, odds);
+        CONFIG.with(|c| {
+            let mut config = c.borrow_mut();
+            config.jackpot_contribution_bps = contribution_bps;
+            config.jackpot_odds = odds;
+        });
+        Ok(())
+    })
+}
+
+/// Configure the minimum number of distinct participants needed for a draw (admin function)
+#[update]
+pub fn set_participant_threshold(min_participants: u32, action: ThresholdAction) -> Result<(), String> {
+    assert_admin();
+    
+    audited("set_participant_threshold", format!("min_participants: {}, action: {:?}", min_participants, action), || {
+        ic_cdk::println!("⚙️ [SET_PARTICIPANT_THRESHOLD] Minimum {} distinct participants, otherwise {:?}", min_participants, action);
+        CONFIG.with(|c| {
+            let mut config = c.borrow_mut();
+            config.min_participants = min_participants;
+            config.below_threshold_action = action;
+        });
+        Ok(())
+    })
+}
+
+// 新增：按开奖时间表计算 after 之后的下一次开奖时间（纳秒），超出 u64 范围时返回 None
+fn scheduled_after(schedule: &DrawSchedule, after: u64) -> Option<u64> {
+    // 周期内的第一个开奖时刻晚于 after 则取它，否则取下一个周期
+    let next_slot = |period: u64, offset: u64| {
+        let slot = (after - after % period).checked_add(offset)?;
+        if slot > after { Some(slot) } else { slot.checked_add(period) }
+    };
+    match schedule {
+        DrawSchedule::Interval { seconds } => after.checked_add(seconds.checked_mul(NANOS_PER_SECOND)?),
+        DrawSchedule::Daily { hour, minute } => next_slot(NANOS_PER_DAY, time_of_day(*hour, *minute)),
+        // Unix 纪元（1970-01-01）是周四，按纪元对齐的周从周四开始
+        DrawSchedule::Weekly { weekday, hour, minute } => 
+            next_slot(7 * NANOS_PER_DAY, (*weekday as u64 + 4) % 7 * NANOS_PER_DAY + time_of_day(*hour, *minute)),
+    }
+}
+
+fn time_of_day(hour: u8, minute: u8) -> u64 {
+    (hour as u64 * 3_600 + minute as u64 * 60) * NANOS_PER_SECOND
+}
+
+// 新增：新一轮（或延长的一轮）从 now 开始时的开奖时间；时间表算出的时间溢出时退回默认轮次时长
+fn next_draw_time(now: u64) -> u64 {
+    CONFIG.with(|c| c.borrow().draw_schedule.clone())
+        .and_then(|schedule| scheduled_after(&schedule, now))
+        .unwrap_or_else(|| now.saturating_add(ROUND_DURATION))
+}
+
+// 新增：从当前轮次的 end_time 起往后推算的开奖时间
+fn upcoming_draws(end_time: u64, count: usize) -> Vec<u64> {
+    std::iter::successors(Some(end_time), |previous| Some(next_draw_time(*previous)).filter(|next| next > previous))
+        .take(count)
+        .collect()
+}
+
+/// Set the draw schedule used from the next round on, e.g. daily at 20:00 UTC or every Sunday (admin function)
+#[update]
+pub fn set_draw_schedule(schedule: DrawSchedule) -> Result<(), String> {
+    assert_admin();
+    
+    audited("set_draw_schedule", format!("{:?}", schedule), || {
+        match schedule {
+            DrawSchedule::Interval { seconds } if !(MIN_DRAW_INTERVAL..=MAX_DRAW_INTERVAL).contains(&seconds) => 
+                return Err(format!("Draw interval must be between {} and {} seconds", MIN_DRAW_INTERVAL, MAX_DRAW_INTERVAL)),
+            DrawSchedule::Daily { hour, minute } | DrawSchedule::Weekly { hour, minute, .. } if hour >= 24 || minute >= 60 => 
+                return Err("Draw time must be a valid UTC hour and minute".to_string()),
+            DrawSchedule::Weekly { weekday, .. } if weekday >= 7 => 
+                return Err("Weekday must be between 0 (Monday) and 6 (Sunday)".to_string()),
+            _ => {}
+        }
+        
+        let next_draw = scheduled_after(&schedule, time()).ok_or_else(|| "Draw schedule is out of range".to_string())?;
+        ic_cdk::println!("⚙️ [SET_DRAW_SCHEDULE] {:?}, next draw after this round at {}", schedule, next_draw);
+        CONFIG.with(|c| c.borrow_mut().draw_schedule = Some(schedule));
+        
+        refresh_certified_data();
+        Ok(())
+    })
+}
+
+/// List upcoming draw times in nanoseconds, starting with the current round's end time
+#[query]
+pub fn list_upcoming_draws(count: u64) -> Vec<u64> {
+    let end_time = CURRENT_ROUND.with(|r| r.borrow().end_time);
+    upcoming_draws(end_time, count.min(MAX_PAGE_SIZE) as usize)
+}
+
+/// Configure ticket caps; 0 disables a limit (admin function)
+#[update]
+pub fn set_ticket_limits(max_tickets_per_user_per_round: u64, max_tickets_per_round: u64, max_daily_stake_per_user: u64) -> Result<(), String> {
+    assert_admin();
+    
+    audited("set_ticket_limits", format!("max_tickets_per_user_per_round: {}, max_tickets_per_round: {}, max_daily_stake_per_user: {}", max_tickets_per_user_per_round, max_tickets_per_round, max_daily_stake_per_user), || {
+        if max_tickets_per_round > 0 && max_tickets_per_user_per_round > max_tickets_per_round {
+            return Err("Per-user ticket limit cannot exceed the per-round ticket limit".to_string());
+        }
+        
+        ic_cdk::println!("⚙️ [SET_TICKET_LIMITS] Per user per round: {}, per round: {}, daily stake per user: {} e8s", 
+                       max_tickets_per_user_per_round, max_tickets_per_round, max_daily_stake_per_user);
+        CONFIG.with(|c| {
+            let mut config = c.borrow_mut();
+            config.max_tickets_per_user_per_round = max_tickets_per_user_per_round;
+            config.max_tickets_per_round = max_tickets_per_round;
+            config.max_daily_stake_per_user = max_daily_stake_per_user;
+        });
+        Ok(())
+    })
+}
+
+#[query]
+pub fn get_jackpot() -> Jackpot {
+    JACKPOT.with(|j| j.borrow().clone())
+}
+
+/// Set the house fee taken from each prize pool at draw time, in basis points (admin function)
+#[update]
+pub fn set_house_fee_bps(fee_bps: u64) -> Result<(), String> {
+    assert_admin();
+    
+    audited("set_house_fee_bps", format!("fee_bps: {}", fee_bps), || {
+        if fee_bps > MAX_HOUSE_FEE_BPS {
+            return Err(format!("House fee cannot exceed {} basis points", MAX_HOUSE_FEE_BPS));
+        }
+        
+        ic_cdk::println!("⚙️ [SET_HOUSE_FEE] House fee set to {} bps", fee_bps);
+        CONFIG.with(|c| {
+            c.borrow_mut().house_fee_bps = fee_bps;
+        });
+        Ok(())
+    })
+}
 
-    ic_cdk::println!("✅ [PLACE_BET] Bet placement successful for user: {}", requested_principal);
+/// Withdraw accrued house fees from the canister main account, which the deposit sweep funds, to an ICRC-1 account (admin function)
+#[update]
+pub async fn withdraw_fees(to: Account, amount: u64) -> Result<String, String> {
+    assert_admin();
     
-    // 保存数据到稳定存储
-    save_to_stable_storage();
+    let arguments = format!("to: {:?}, amount: {}", to, amount);
+    let result = execute_fee_withdrawal(to, amount).await;
+    audit("withdraw_fees", arguments, result.clone());
+    result
 }
 
-#[update]
-pub async fn withdraw_balance(principal_str: String, amount: u64) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    ic_cdk::println!("💸 [WITHDRAW] Withdrawal requested by: {}", caller);
-    ic_cdk::println!("💸 [WITHDRAW] Requested principal: {}", principal_str);
-    ic_cdk::println!("💸 [WITHDRAW] Amount: {} e8s", amount);
+async fn execute_fee_withdrawal(to: Account, amount: u64) -> Result<String, String> {
+    if solvency_paused() {
+        return Err("Withdrawals are paused until the platform is solvent again".to_string());
+    }
     
-    let requested_principal = match Principal::from_text(&principal_str) {
-        Ok(p) => p,
-        Err(e) => {
-            ic_cdk::println!("❌ [WITHDRAW] Invalid principal format: {}", e);
-            return Err(format!("Invalid principal format: {}", e));
+    // 先扣减已累计的平台费（含 ledger 手续费），转账失败时再退回，避免并发重复提取
+    let debit = amount.checked_add(CKBTC_TRANSFER_FEE).ok_or_else(|| "Withdrawal amount is too large".to_string())?;
+    FEE_ACCOUNT.with(|f| {
+        let mut fees = f.borrow_mut();
+        if fees.accrued < debit {
+            return Err(format!("Insufficient accrued fees. Accrued {} but withdrawal needs {} (including ledger fee)", fees.accrued, debit));
         }
-    };
+        fees.accrued -= debit;
+        Ok(())
+    })?;
+    post(LedgerAccount::Fees, LedgerAccount::Treasury, debit, "fee withdrawal including ledger fee".to_string());
+    refresh_certified_data();
     
-    // 检查用户余额
-    let user_info = USERS.with(|users| {
-        users.borrow().get(&requested_principal).cloned()
-    });
+    ic_cdk::println!("🏦 [WITHDRAW_FEES] Withdrawing {} e8s of fees to {:?}", amount, to);
     
-    if let Some(user) = user_info {
-        if user.balance < amount {
-            ic_cdk::println!("❌ [WITHDRAW] INSUFFICIENT BALANCE: User has {} but wants to withdraw {}", user.balance, amount);
-            return Err(format!("Insufficient balance for withdrawal. User has {} but wants to withdraw {}", user.balance, amount));
-        }
-        
-        // 检查用户的 deposit account 是否有足够的链上余额
-        let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
-        let deposit_account_balance = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (user.deposit_account.clone(),)).await {
-            Ok((balance,)) => balance.0.try_into().unwrap_or(0),
-            Err(_) => 0,
-        };
-        
-        if deposit_account_balance < amount {
-            ic_cdk::println!("❌ [WITHDRAW] INSUFFICIENT CHAIN BALANCE: Deposit account has {} but needs {}", deposit_account_balance, amount);
-            return Err(format!("Insufficient chain balance. Deposit account has {} but needs {}. Please wait for deposits to confirm.", deposit_account_balance, amount));
+    let transfer_args = TransferArgs {
+        to,
+        amount,
+        fee: Some(CKBTC_TRANSFER_FEE),
+        memo: Some("Lottery house fee withdrawal".as_bytes().to_vec()),
+        from_subaccount: None,
+        created_at_time: Some(time()),
+    };
+    
+    let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
+    let result = match ic_cdk::call::<_, (TransferResult,)>(ckbtc_canister, "icrc1_transfer", (transfer_args,)).await {
+        Ok((TransferResult::Ok(block_index),)) => {
+            ic_cdk::println!("✅ [WITHDRAW_FEES] Fee withdrawal successful! Block index: {}", block_index);
+            FEE_ACCOUNT.with(|f| {
+                f.borrow_mut().total_withdrawn += amount;
+            });
+            Ok(format!("Fee withdrawal successful! Block index: {}", block_index))
+        },
+        Ok((TransferResult::Err(error),)) => {
+            log_error("withdraw_fees", None, ErrorKind::LedgerRejected, format!("Fee transfer of {} e8s failed: {:?}", amount, error));
+            Err(format!("Fee transfer failed: {:?}", error))
+        },
+        Err(error) => {
+            log_error("withdraw_fees", None, ErrorKind::LedgerCall, format!("Fee transfer of {} e8s: call to ckBTC canister failed: {:?}", amount, error));
+            Err(format!("Call to ckBTC canister failed: {:?}", error))
         }
-        
-        // 记录提现前的余额信息
-        ic_cdk::println!("💰 [WITHDRAW] User local balance: {} e8s", user.balance);
-        ic_cdk::println!("💰 [WITHDRAW] User deposit account balance: {} e8s", deposit_account_balance);
-        
-        // 从用户的 deposit account 提现给用户
-        
-        // 创建转账参数 - 从用户的 deposit account 转给用户
-        let transfer_args = TransferArgs {
-            to: Account {
-                owner: requested_principal,
-                subaccount: None,
-            },
-            amount,
-            fee: Some(1_000), // 0.00001 ckBTC fee
-            memo: Some(format!("Lottery withdrawal from user deposit account").into_bytes()),
-            from_subaccount: user.deposit_account.subaccount.clone(), // ✅ 从用户的 deposit account 转账
-            created_at_time: Some(time()),
-        };
-        
-            match ic_cdk::call::<_, (TransferResult,)>(ckbtc_canister, "icrc1_transfer", (transfer_args,)).await {
-                Ok((result,)) => {
-                    match result {
-                        TransferResult::Ok(block_index) => {
-                        ic_cdk::println!("✅ [WITHDRAW] Withdrawal from treasury successful! Block index: {}", block_index);
-                            
-                            // 更新用户余额
-                            USERS.with(|users| {
-                                let mut users_ref = users.borrow_mut();
-                                if let Some(user) = users_ref.get_mut(&requested_principal) {
-                                    user.balance -= amount;
-                                    
-                                    // 记录提现交易
-                                    user.transaction_history.push(Transaction {
-                                        amount,
-                                        timestamp: time(),
-                                        transaction_type: "Withdraw".to_string(),
-                                        tx_hash: Some(format!("withdraw_{}", block_index)),
-                                    ckbtc_address: Some(format!("User Account: {}", requested_principal)),
-                                    });
-                                }
-                            });
-                        
-                        // 保存数据到稳定存储
-                        save_to_stable_storage();
-                        
-                        Ok(format!("Withdrawal successful! Block index: {}", block_index))
-                        },
-                        TransferResult::Err(error) => {
-                        ic_cdk::println!("❌ [WITHDRAW] Transfer from treasury failed: {:?}", error);
-                        Err(format!("Transfer from treasury failed: {:?}", error))
-                        }
-                    }
-                },
-                Err(error) => {
-                    ic_cdk::println!("❌ [WITHDRAW] Call to ckBTC canister failed: {:?}", error);
-                Err(format!("Call to ckBTC canister failed: {:?}", error))
-                }
-            }
-    } else {
-        Err("User not found".to_string())
+    };
+    
+    if result.is_err() {
+        FEE_ACCOUNT.with(|f| {
+            f.borrow_mut().accrued += debit;
+        });
+        post(LedgerAccount::Treasury, LedgerAccount::Fees, debit, "failed fee withdrawal reversed".to_string());
+        refresh_certified_data();
     }
+    
+    result
 }
 
 #[query]
-pub fn get_user(principal: Principal) -> Option<User> {
-    ic_cdk::println!("🔍 [GET_USER] Looking up user: {}", principal);
-    let result = USERS.with(|users| users.borrow().get(&principal).cloned());
-    if let Some(ref user) = result {
-        ic_cdk::println!("✅ [GET_USER] User found: {}", principal);
-        ic_cdk::println!("✅ [GET_USER] User balance: {} e8s ({} ckBTC)", 
-                       user.balance, user.balance as f64 / 100_000_000.0);
-    } else {
-        ic_cdk::println!("❌ [GET_USER] User not found: {}", principal);
+pub fn get_fee_account() -> FeeAccount {
+    FEE_ACCOUNT.with(|f| f.borrow().clone())
+}
+
+/// Check that the internal ledger balances and matches every account balance (admin function)
+#[query]
+pub fn verify_ledger_invariants() -> LedgerReport {
+    assert_admin();
+    check_ledger_invariants()
+}
+
+/// Run a solvency reconciliation now instead of waiting for the periodic job (admin function)
+#[update]
+pub async fn run_solvency_check() -> SolvencyReport {
+    assert_admin();
+    reconcile_solvency().await
+}
+
+/// Get the latest solvency report, if any
+#[query]
+pub fn get_solvency_report() -> Option<SolvencyReport> {
+    SOLVENCY.with(|s| s.borrow().reports.values().next_back().cloned())
+}
+
+/// Get stored solvency reports, newest first
+#[query]
+pub fn list_solvency_reports(start: u64, limit: u64) -> Vec<SolvencyReport> {
+    SOLVENCY.with(|s| {
+        s.borrow().reports.values().rev()
+            .skip(start as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Whether betting and withdrawals are paused because liabilities exceed on-chain assets
+#[query]
+pub fn is_solvency_paused() -> bool {
+    solvency_paused()
+}
+
+fn assert_admin_or_auditor() {
+    let caller = ic_cdk::caller();
+    let is_admin = ADMIN.with(|a| *a.borrow() == Some(caller));
+    if !is_admin && !AUDITORS.with(|auditors| auditors.borrow().contains(&caller)) {
+        ic_cdk::trap("Only admin or auditors can read the audit and error logs");
     }
-    result
 }
 
+/// Grant a principal read access to the audit log (admin function)
 #[update]
-pub fn trigger_draw() {
+pub fn add_auditor(principal: Principal) -> Result<(), String> {
     assert_admin();
+    
+    audited("add_auditor", format!("principal: {}", principal), || {
+        AUDITORS.with(|auditors| {
+            let mut auditors = auditors.borrow_mut();
+            if auditors.contains(&principal) {
+                return Err("Principal is already an auditor".to_string());
+            }
+            auditors.push(principal);
+            Ok(())
+        })
+    })
+}
 
-    // 移除开奖前插入假用户的调用
-    // insert_fake_users_if_needed();
+/// Revoke a principal's read access to the audit log (admin function)
+#[update]
+pub fn remove_auditor(principal: Principal) -> Result<(), String> {
+    assert_admin();
+    
+    audited("remove_auditor", format!("principal: {}", principal), || {
+        AUDITORS.with(|auditors| {
+            let mut auditors = auditors.borrow_mut();
+            let len = auditors.len();
+            auditors.retain(|p| *p != principal);
+            if auditors.len() == len {
+                return Err("Principal is not an auditor".to_string());
+            }
+            Ok(())
+        })
+    })
+}
 
-    let winner = CURRENT_ROUND.with(|r| {
-        let mut round = r.borrow_mut();
-        if round.participants.is_empty() {
-            ic_cdk::trap("No participants");
+/// Get audit log entries, newest first, optionally restricted to one action (admin or auditor)
+#[query]
+pub fn get_audit_log(start: u64, limit: u64, action: Option<String>) -> AuditPage {
+    assert_admin_or_auditor();
+    
+    AUDIT_INDEX.with(|index| {
+        let index = index.borrow();
+        // 未出现过的操作名没有编号，不匹配任何记录
+        let action_id = action.map(|a| index.actions.iter().position(|known| *known == a));
+        let matching = || index.entries.iter().rev().filter(|(_, id)| action_id.is_none_or(|a| a == Some(*id as usize)));
+        AuditPage {
+            entries: matching().skip(start as usize).take(limit.min(MAX_PAGE_SIZE) as usize).map(|(offset, _)| read_audit_entry(*offset)).collect(),
+            total: matching().count() as u64,
         }
-        let idx = time() as usize % round.participants.len();
-        let winner = round.participants[idx];
-        round.winners = vec![winner];
-        round.clone()
-    });
+    })
+}
+
+/// Get the most recent ledger postings, newest first; older postings are dropped, so the journal cannot rebuild balances (admin function)
+#[query]
+pub fn get_ledger_journal(limit: u64) -> Vec<Posting> {
+    assert_admin();
+    LEDGER.with(|l| l.borrow().journal.values().rev().take(limit.min(MAX_PAGE_SIZE) as usize).cloned().collect())
+}
+
+#[query]
+pub fn get_lottery_config() -> LotteryConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
 
-    // 计算总奖池（包括假用户的贡献）
-    let total_prize_pool = winner.participants.len() as u64 * TICKET_PRICE;
+/// Register a liquidity bot or update how many tickets it buys each round (admin function)
+#[update]
+pub fn add_bot(principal: Principal, tickets_per_round: u32) -> Result<(), String> {
+    assert_admin();
     
-    USERS.with(|users| {
-        let mut users_ref = users.borrow_mut();
-        if let Some(user) = users_ref.get_mut(&winner.winners[0]) {
-            user.balance += total_prize_pool;
-            user.transaction_history.push(Transaction {
-                amount: total_prize_pool,
-                timestamp: time(),
-                transaction_type: "Win".to_string(),
-                tx_hash: None,
-                ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-            });
-            user.winning_history.push(Winning {
-                amount: total_prize_pool,
-                timestamp: time(),
-                round_id: winner.id,
-            });
+    audited("add_bot", format!("principal: {}, tickets_per_round: {}", principal, tickets_per_round), || {
+        if USERS.with(|users| users.borrow().contains_key(&principal)) {
+            return Err("Principal is a registered user and cannot be used as a bot".to_string());
         }
-    });
-
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        stats.total_rounds += 1;
-        stats.total_winnings += total_prize_pool;
-    });
+        
+        BOTS.with(|bots| {
+            bots.borrow_mut().entry(principal)
+                .and_modify(|bot| bot.tickets_per_round = tickets_per_round)
+                .or_insert(BotAccount {
+                    principal,
+                    balance: 0,
+                    tickets_per_round,
+                    total_funded: 0,
+                    total_bet: 0,
+                    total_won: 0,
+                });
+        });
+        
+        ic_cdk::println!("🤖 [ADD_BOT] Bot {} buys {} tickets per round", principal, tickets_per_round);
+        Ok(())
+    })
+}
 
-    // 记录历史中奖记录
-    HISTORICAL_WINNERS.with(|winners| {
-        let mut winners_ref = winners.borrow_mut();
-        winners_ref.push(HistoricalWinner {
-            winner_principal: winner.winners[0].to_string(),
-            amount: total_prize_pool,
-            timestamp: time(),
-            round_id: winner.id,
+/// Remove a liquidity bot and return its remaining balance to the treasury (admin function)
+#[update]
+pub fn remove_bot(principal: Principal) -> Result<u64, String> {
+    assert_admin();
+    
+    audited("remove_bot", format!("principal: {}", principal), || {
+        let bot = BOTS.with(|bots| bots.borrow_mut().remove(&principal))
+            .ok_or_else(|| "Bot not found".to_string())?;
+        FEE_ACCOUNT.with(|f| {
+            f.borrow_mut().accrued += bot.balance;
         });
+        post(LedgerAccount::Bot(principal), LedgerAccount::Fees, bot.balance, "bot removed".to_string());
         
-        // 只保留最近10次中奖记录
-        if winners_ref.len() > 10 {
-            winners_ref.remove(0);
-        }
-    });
+        ic_cdk::println!("🤖 [REMOVE_BOT] Bot {} removed, {} e8s returned to treasury", principal, bot.balance);
+        refresh_certified_data();
+        Ok(bot.balance)
+    })
+}
 
-    // 新建新轮次时插入随机假用户并自动累加奖池
-    let mut new_round = Round {
-        id: winner.id + 1,
-        participants: vec![],
-        prize_pool: 0,
-        start_time: time(),
-        end_time: time() + ROUND_DURATION,
-        winners: vec![],
-    };
+/// Move funds from the treasury (accrued house fees) to a liquidity bot (admin function)
+#[update]
+pub fn fund_bot(principal: Principal, amount: u64) -> Result<u64, String> {
+    assert_admin();
     
-    // 随机选择假用户参与并让它们真实下注
-    let random_fakes = get_random_fake_users();
-    for fake_principal in random_fakes {
-        // 确保假用户已初始化
-        initialize_fake_users();
+    audited("fund_bot", format!("principal: {}, amount: {}", principal, amount), || {
+        if !BOTS.with(|bots| bots.borrow().contains_key(&principal)) {
+            return Err("Bot not found".to_string());
+        }
         
-        // 让假用户真实下注（扣除余额）
-        USERS.with(|users| {
-            let mut users_ref = users.borrow_mut();
-            if let Some(user) = users_ref.get_mut(&fake_principal) {
-                if user.balance >= TICKET_PRICE {
-                    let old_balance = user.balance;
-                    user.balance -= TICKET_PRICE;
-                    
-                    // 记录假用户下注交易
-                    let transaction = Transaction {
-                        amount: TICKET_PRICE,
-                        timestamp: time(),
-                        transaction_type: "Bet".to_string(),
-                        tx_hash: None,
-                        ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-                    };
-                    user.transaction_history.push(transaction);
-                    
-                    ic_cdk::println!("🤖 [FAKE_BET] Fake user {} placed bet: {} -> {} e8s", 
-                                   fake_principal, old_balance, user.balance);
-                } else {
-                    ic_cdk::println!("❌ [FAKE_BET] Fake user {} insufficient balance: {} e8s", 
-                                   fake_principal, user.balance);
-                }
+        FEE_ACCOUNT.with(|f| {
+            let mut fees = f.borrow_mut();
+            if fees.accrued < amount {
+                return Err(format!("Insufficient treasury funds. Accrued {} but funding needs {}", fees.accrued, amount));
             }
+            fees.accrued -= amount;
+            Ok(())
+        })?;
+        post(LedgerAccount::Fees, LedgerAccount::Bot(principal), amount, "bot funding".to_string());
+        
+        let balance = BOTS.with(|bots| {
+            let mut bots_ref = bots.borrow_mut();
+            let bot = bots_ref.get_mut(&principal).expect("bot checked above");
+            bot.balance += amount;
+            bot.total_funded += amount;
+            bot.balance
         });
         
-        new_round.participants.push(fake_principal);
-        new_round.prize_pool += TICKET_PRICE;
-    }
-    CURRENT_ROUND.with(|r| {
-        *r.borrow_mut() = new_round;
-    });
+        ic_cdk::println!("🤖 [FUND_BOT] Bot {} funded with {} e8s, balance: {} e8s", principal, amount, balance);
+        refresh_certified_data();
+        Ok(balance)
+    })
+}
+
+/// Move funds from a liquidity bot back to the treasury (admin function)
+#[update]
+pub fn defund_bot(principal: Principal, amount: u64) -> Result<u64, String> {
+    assert_admin();
     
-    // 保存数据到稳定存储
-    save_to_stable_storage();
+    audited("defund_bot", format!("principal: {}, amount: {}", principal, amount), || {
+        let balance = BOTS.with(|bots| {
+            let mut bots_ref = bots.borrow_mut();
+            let bot = bots_ref.get_mut(&principal).ok_or_else(|| "Bot not found".to_string())?;
+            if bot.balance < amount {
+                return Err(format!("Insufficient bot balance. Bot has {} but {} was requested", bot.balance, amount));
+            }
+            bot.balance -= amount;
+            Ok(bot.balance)
+        })?;
+        FEE_ACCOUNT.with(|f| {
+            f.borrow_mut().accrued += amount;
+        });
+        post(LedgerAccount::Bot(principal), LedgerAccount::Fees, amount, "bot defunding".to_string());
+        
+        ic_cdk::println!("🤖 [DEFUND_BOT] Bot {} returned {} e8s to treasury, balance: {} e8s", principal, amount, balance);
+        refresh_certified_data();
+        Ok(balance)
+    })
+}
+
+#[query]
+pub fn list_bots() -> Vec<BotAccount> {
+    BOTS.with(|bots| bots.borrow().values().cloned().collect())
 }
 
+/// Get the 10 most recent winners, oldest first
 #[query]
 pub fn get_historical_winners() -> Vec<HistoricalWinner> {
     HISTORICAL_WINNERS.with(|winners| {
-        winners.borrow().clone()
+        let winners = winners.borrow();
+        winners[winners.len().saturating_sub(10)..].to_vec()
+    })
+}
+
+/// Get every winner ever recorded, newest first
+#[query]
+pub fn list_winners(start: u64, limit: u64) -> WinnerPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    HISTORICAL_WINNERS.with(|winners| {
+        let winners = winners.borrow();
+        WinnerPage {
+            winners: winners.iter().rev().skip(start as usize).take(limit).cloned().collect(),
+            total: winners.len() as u64,
+        }
+    })
+}
+
+/// Get an archived round, including its settlement and randomness proof
+#[query]
+pub fn get_round_by_id(round_id: u64) -> Option<ArchivedRound> {
+    ROUND_ARCHIVE.with(|archive| archive.borrow().get(&round_id).cloned())
+}
+
+/// Get archived rounds, newest first; rounds without participants are not archived
+#[query]
+pub fn list_rounds(start: u64, limit: u64) -> RoundPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    ROUND_ARCHIVE.with(|archive| {
+        let archive = archive.borrow();
+        RoundPage {
+            rounds: archive.values().rev().skip(start as usize).take(limit).cloned().collect(),
+            total: archive.len() as u64,
+        }
     })
 }
 
 #[query]
 pub fn get_round() -> Round {
-    // 确保定时器已初始化
-    ensure_timer_initialized();
-    
-    CURRENT_ROUND.with(|r| r.borrow().clone())
+    current_round_view()
+}
+
+// 新增：对外展示的当前轮次，附带累积奖金和每个参与者的中奖概率
+fn current_round_view() -> Round {
+    let mut round = CURRENT_ROUND.with(|r| r.borrow().clone());
+    round.jackpot = Some(JACKPOT.with(|j| j.borrow().balance));
+    round.upcoming_draws = Some(upcoming_draws(round.end_time, UPCOMING_DRAWS_IN_ROUND));
+    let total_tickets = round.total_tickets.max(1) as f64;
+    for participant in round.participants.iter_mut() {
+        participant.win_probability = participant.ticket_count as f64 / total_tickets;
+    }
+    round
 }
 
 #[query]
 pub fn get_stats() -> SystemStats {
-    STATS.with(|s| s.borrow().clone())
+    let mut stats = STATS.with(|s| s.borrow().clone());
+    FEE_ACCOUNT.with(|f| {
+        let fees = f.borrow();
+        stats.total_fees_collected = Some(fees.total_collected);
+        stats.fees_accrued = Some(fees.accrued);
+    });
+    stats
 }
 
 #[query]
@@ -1149,59 +3488,38 @@ pub fn get_all_users_debug() -> Vec<String> {
     })
 }
 
-/// Record a ckBTC deposit transaction
+/// Record a pending ckBTC deposit; the balance is credited only once the transfer is seen on the ledger by the deposit sync
 #[update]
 pub fn record_ckbtc_deposit(tx_hash: String, amount: u64) {
     let caller = ic_cdk::caller();
     
     // Create user if it doesn't exist
-    USERS.with(|users| {
+    let created = USERS.with(|users| {
         let mut users_ref = users.borrow_mut();
-        if !users_ref.contains_key(&caller) {
-            users_ref.insert(caller, User {
-                balance: 0,
-                transaction_history: vec![],
-                winning_history: vec![],
-                deposit_account: Account {
-                    owner: caller,
-                    subaccount: None,
-                },
-                principal_text: caller.to_string(),
-                last_balance_check: time(),
-            });
+        if let Entry::Vacant(entry) = users_ref.entry(caller) {
+            entry.insert(new_user(caller));
             STATS.with(|s| s.borrow_mut().active_users += 1);
+            true
+        } else {
+            false
         }
     });
     
-    // Record the deposit
+    // 只记录调用者声明的充值，金额未经验证，不计入余额；入账统一由充值账户的高水位同步完成
     CKBTC_DEPOSITS.with(|deposits| {
-        deposits.borrow_mut().insert(tx_hash.clone(), CkBtcDeposit {
-            principal: caller.to_string(),
-            amount,
-            tx_hash: tx_hash.clone(),
-            timestamp: time(),
-            status: "pending".to_string(),
-        });
-    });
-    
-    // Add to user's balance and transaction history
-    USERS.with(|users| {
-        if let Some(user) = users.borrow_mut().get_mut(&caller) {
-            user.balance += amount;
-            user.transaction_history.push(Transaction {
-                amount,
-                timestamp: time(),
-                transaction_type: "CkBtcDeposit".to_string(),
-                tx_hash: Some(tx_hash),
-                ckbtc_address: Some(format!("{:?}", user.deposit_account)),
-            });
-        }
+        deposits.borrow_mut().insert(tx_hash.clone(), CkBtcDeposit {
+            principal: caller.to_string(),
+            amount,
+            tx_hash: tx_hash.clone(),
+            timestamp: time(),
+            status: "pending".to_string(),
+        });
     });
     
-    // Update stats
-    STATS.with(|s| {
-        s.borrow_mut().total_ckbtc_deposits += amount;
-    });
+    if created {
+        refresh_certified_data();
+    }
+    audit("record_ckbtc_deposit", format!("tx_hash: {}, amount: {}", tx_hash, amount), Ok("recorded as pending".to_string()));
 }
 
 /// Get all ckBTC deposits for a user
@@ -1233,28 +3551,84 @@ pub fn get_pending_ckbtc_deposits() -> Vec<CkBtcDeposit> {
 pub fn confirm_ckbtc_deposit(tx_hash: String) {
     assert_admin();
     
-    CKBTC_DEPOSITS.with(|deposits| {
-        if let Some(deposit) = deposits.borrow_mut().get_mut(&tx_hash) {
+    let confirmed = CKBTC_DEPOSITS.with(|deposits| {
+        deposits.borrow_mut().get_mut(&tx_hash).map(|deposit| {
             deposit.status = "confirmed".to_string();
-        }
+            deposit.amount
+        })
     });
+    audit("confirm_ckbtc_deposit", format!("tx_hash: {}", tx_hash),
+          confirmed.map(|amount| format!("confirmed {} e8s", amount)).ok_or_else(|| "Deposit not found".to_string()));
 }
 
 
-thread_local! {
-    static LAST_ERROR_LOG: std::cell::RefCell<Option<String>> = std::cell::RefCell::new(None);
+// 新增：执行一个特权操作并把调用者、参数和结果写入审计日志
+fn audited<T: std::fmt::Debug>(action: &str, arguments: String, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let result = f();
+    audit(action, arguments, result.as_ref().map(|value| format!("{:?}", value)).map_err(|e| e.clone()));
+    result
+}
+
+// 新增：追加一条审计日志，直接写入稳定内存，永不淘汰
+fn audit(action: &str, arguments: String, outcome: Result<String, String>) {
+    let id = AUDIT_INDEX.with(|index| index.borrow().next_id);
+    ic_cdk::println!("📝 [AUDIT] #{} {} ({}) -> {:?}", id, action, arguments, outcome);
+    append_audit_entry(&AuditEntry {
+        id,
+        timestamp: time(),
+        caller: ic_cdk::caller(),
+        action: action.to_string(),
+        arguments,
+        outcome,
+    });
 }
 
-fn log_error(msg: String) {
-    ic_cdk::println!("[ERROR] {}", msg); // 仍然保留本地开发输出
-    LAST_ERROR_LOG.with(|log| {
-        *log.borrow_mut() = Some(msg);
+// 新增：把错误事件追加到有界环形缓冲区，超过 MAX_ERROR_EVENTS 时丢弃最旧的
+fn log_error(component: &str, principal: Option<Principal>, kind: ErrorKind, message: String) {
+    ic_cdk::println!("❌ [ERROR] [{}] {:?} {:?}: {}", component, kind, principal, message); // 仍然保留本地开发输出
+    ERROR_COUNTS.with(|counts| {
+        *counts.borrow_mut().entry((component.to_string(), kind.clone())).or_insert(0) += 1;
+    });
+    ERROR_LOG.with(|log| {
+        let mut log_ref = log.borrow_mut();
+        let id = log_ref.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        log_ref.insert(id, ErrorEvent {
+            id,
+            timestamp: time(),
+            component: component.to_string(),
+            principal,
+            kind,
+            message,
+        });
+        while log_ref.len() > MAX_ERROR_EVENTS {
+            log_ref.pop_first();
+        }
     });
 }
 
+/// Get the most recent error message (admin or auditor)
 #[query]
 pub fn get_last_error_log() -> Option<String> {
-    LAST_ERROR_LOG.with(|log| log.borrow().clone())
+    assert_admin_or_auditor();
+    ERROR_LOG.with(|log| log.borrow().last_key_value().map(|(_, event)| event.message.clone()))
+}
+
+/// Get error events, newest first, optionally filtered by component and a [from, to] time range in nanoseconds (admin or auditor)
+#[query]
+pub fn get_error_log(component: Option<String>, from: Option<u64>, to: Option<u64>, start: u64, limit: u64) -> ErrorPage {
+    assert_admin_or_auditor();
+    ERROR_LOG.with(|log| {
+        let log_ref = log.borrow();
+        let matching = || log_ref.values().rev().filter(|event| {
+            component.as_ref().is_none_or(|c| &event.component == c)
+                && from.is_none_or(|from| event.timestamp >= from)
+                && to.is_none_or(|to| event.timestamp <= to)
+        });
+        ErrorPage {
+            events: matching().skip(start as usize).take(limit.min(MAX_PAGE_SIZE) as usize).cloned().collect(),
+            total: matching().count() as u64,
+        }
+    })
 }
 
 /// Get detailed user information for debugging
@@ -1268,15 +3642,23 @@ pub fn get_user_debug_info(principal: Principal) -> String {
             debug_info.push_str(&format!("User Principal: {}\n", principal));
             debug_info.push_str(&format!("Balance: {} e8s ({} ckBTC)\n", 
                                        user.balance, user.balance as f64 / 100_000_000.0));
-            debug_info.push_str(&format!("Transaction History Count: {}\n", user.transaction_history.len()));
+            debug_info.push_str(&format!("Transaction History Count: {}\n", user.transaction_count));
             debug_info.push_str(&format!("Winning History Count: {}\n", user.winning_history.len()));
             debug_info.push_str(&format!("CkBTC Address: {:?}\n", user.deposit_account));
             
             // Show recent transactions
             debug_info.push_str("\nRecent Transactions:\n");
-            for (i, tx) in user.transaction_history.iter().rev().take(5).enumerate() {
+            let recent = TRANSACTION_LOG.with(|log| {
+                log.borrow()
+                    .range((principal, 0)..=(principal, u64::MAX))
+                    .rev()
+                    .take(5)
+                    .map(|(_, tx)| tx.clone())
+                    .collect::<Vec<_>>()
+            });
+            for (i, tx) in recent.iter().enumerate() {
                 debug_info.push_str(&format!("  {}. Type: {}, Amount: {} e8s, Time: {}\n", 
-                                           i + 1, tx.transaction_type, tx.amount, tx.timestamp));
+                                           i + 1, tx.kind.name(), tx.amount, tx.timestamp));
             }
         } else {
             debug_info.push_str(&format!("User not found: {}\n", principal));
@@ -1286,7 +3668,7 @@ pub fn get_user_debug_info(principal: Principal) -> String {
     // Get current round info
     CURRENT_ROUND.with(|r| {
         let round = r.borrow();
-        debug_info.push_str(&format!("\nCurrent Round Info:\n"));
+        debug_info.push_str("\nCurrent Round Info:\n");
         debug_info.push_str(&format!("Round ID: {}\n", round.id));
         debug_info.push_str(&format!("Participants: {}\n", round.participants.len()));
         debug_info.push_str(&format!("Prize Pool: {} e8s ({} ckBTC)\n", 
@@ -1295,14 +3677,14 @@ pub fn get_user_debug_info(principal: Principal) -> String {
         debug_info.push_str(&format!("End Time: {}\n", round.end_time));
         
         // Check if user is in current round
-        let is_participant = round.participants.contains(&principal);
+        let is_participant = round.participants.iter().any(|p| p.principal == principal);
         debug_info.push_str(&format!("User in current round: {}\n", is_participant));
     });
     
     // Get system stats
     STATS.with(|s| {
         let stats = s.borrow();
-        debug_info.push_str(&format!("\nSystem Stats:\n"));
+        debug_info.push_str("\nSystem Stats:\n");
         debug_info.push_str(&format!("Total Rounds: {}\n", stats.total_rounds));
         debug_info.push_str(&format!("Total Bets: {}\n", stats.total_bets));
         debug_info.push_str(&format!("Total Winnings: {} e8s\n", stats.total_winnings));
@@ -1328,10 +3710,8 @@ pub async fn get_user_ckbtc_balance(principal: Principal) -> Nat {
             balance
         },
         Err(e) => {
-            let msg = format!("❌ Failed to get ckBTC balance for {}: {:?}", principal, e);
-            log_error(msg.clone());
-     
-            ic_cdk::println!("❌ Error calling ckBTC canister: {:?}", e);
+            // 调用方指定的查询失败不写入共享的错误日志，避免任何人都能挤掉真实的错误
+            ic_cdk::println!("❌ Failed to get ckBTC balance for {}: {:?}", principal, e);
             Nat::from(0u64)
         }
     }
@@ -1400,7 +3780,7 @@ pub async fn get_ckbtc_account_balance(owner: String, subaccount_hex: Option<Str
     
     let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
     
-    match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (account,)).await {
+    match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (account.clone(),)).await {
         Ok((balance,)) => {
             let balance_u64: u64 = balance.0.try_into().unwrap_or(0);
             ic_cdk::println!("✅ [GET_CKBTC_ACCOUNT_BALANCE] Account balance: {} e8s", balance_u64);
@@ -1414,293 +3794,650 @@ pub async fn get_ckbtc_account_balance(owner: String, subaccount_hex: Option<Str
 }
 
 
-// 新增：内部自动归集用户账户函数
-async fn auto_consolidate_user_accounts(principal: Principal) -> Result<u64, String> {
-    ic_cdk::println!("🔄 [AUTO_CONSOLIDATE_USER] Consolidating accounts for user: {}", principal);
-    
-    // 获取用户信息
-    let user = USERS.with(|users| {
-        users.borrow().get(&principal).cloned()
-    });
-    
-    if user.is_none() {
-        return Err("User not found".to_string());
+// 新增：同步用户充值账户（canister 名下的子账户）的新入账。高水位记录已入账的流入总额和本 canister 转出的总额，
+// 只把新观察到的流入记入余额，从不覆盖游戏内的余额变动
+async fn sync_user_deposits(principal: Principal) -> Result<u64, String> {
+    let account = USERS.with(|users| {
+        users.borrow().get(&principal).map(|user| user.deposit_account.clone())
+    }).ok_or_else(|| "User not found".to_string())?;
+    if account.owner != ic_cdk::id() {
+        return Err("Deposit account is not held by this canister".to_string());
     }
+    // 提现在途时链上余额与已记录的转出不一致：开始前或查询期间有提现的，跳过本次同步
+    if withdrawal_in_flight(principal) {
+        ic_cdk::println!("⏳ [SYNC_DEPOSITS] Withdrawal in flight for {}, skipping sync", principal);
+        return Ok(0);
+    }
+    let outflows_before = recorded_outflows(principal, &account);
     
-    let user = user.unwrap();
     let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
-    let mut total_consolidated = 0u64;
-    
-    // 1. 检查 deposit account 余额
-    let deposit_balance = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (user.deposit_account.clone(),)).await {
-        Ok((balance,)) => {
-            let balance_u64: u64 = balance.0.try_into().unwrap_or(0);
-            ic_cdk::println!("💰 [AUTO_CONSOLIDATE_USER] Deposit account balance: {} e8s", balance_u64);
-            balance_u64
-        },
+    let chain_balance: u64 = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (account.clone(),)).await {
+        Ok((balance,)) => balance.0.try_into().unwrap_or(0),
         Err(error) => {
-            ic_cdk::println!("❌ [AUTO_CONSOLIDATE_USER] Failed to get deposit account balance: {:?}", error);
-            0
+            log_error("sync_deposits", Some(principal), ErrorKind::LedgerCall, format!("Failed to get balance of {:?}: {:?}", account, error));
+            return Err(format!("Failed to get balance: {:?}", error));
         }
     };
+    if withdrawal_in_flight(principal) || recorded_outflows(principal, &account) != outflows_before {
+        ic_cdk::println!("⏳ [SYNC_DEPOSITS] Withdrawal from {:?} during sync, skipping stale balance", account);
+        return Ok(0);
+    }
     
-    // 2. 如果 deposit account 有余额，记录但不转移（权限问题）
-    if deposit_balance > 0 {
-        ic_cdk::println!("💰 [AUTO_CONSOLIDATE_USER] Found {} e8s in deposit account, but cannot transfer due to permissions", deposit_balance);
-        total_consolidated += deposit_balance;
+    let credited = USERS.with(|users| {
+        let mut users_ref = users.borrow_mut();
+        let user = users_ref.get_mut(&principal)?;
+        let now = time();
+        let allowance = deposit_allowance(principal, user, now);
         
-        // 更新用户余额记录（仅记录，不实际转移）
-        USERS.with(|users| {
-            let mut users_ref = users.borrow_mut();
-            if let Some(user) = users_ref.get_mut(&principal) {
-                user.balance += deposit_balance;
-                user.transaction_history.push(Transaction {
-                    amount: deposit_balance,
-                    timestamp: time(),
-                    transaction_type: "BalanceRecorded".to_string(),
-                    tx_hash: Some(format!("balance_recorded_{}", time())),
-                    ckbtc_address: Some(format!("Deposit Account: {}", principal)),
-                });
+        let idx = match user.deposit_watermarks.iter().position(|w| w.account == account) {
+            Some(idx) => idx,
+            None => {
+                user.deposit_watermarks.push(DepositWatermark { account: account.clone(), credited: 0, outflows: 0, last_synced: now });
+                user.deposit_watermarks.len() - 1
             }
-        });
-        
-        // 更新统计
-        STATS.with(|s| {
-            s.borrow_mut().total_ckbtc_deposits += deposit_balance;
-        });
-    }
-    
-    // 3. 检查主账户余额（没有 subaccount）
-    let main_account = Account {
-        owner: principal,
-        subaccount: None,
-    };
-    
-    let main_balance = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (main_account,)).await {
-        Ok((balance,)) => {
-            let balance_u64: u64 = balance.0.try_into().unwrap_or(0);
-            ic_cdk::println!("💰 [AUTO_CONSOLIDATE_USER] Main account balance: {} e8s", balance_u64);
-            balance_u64
-        },
-        Err(error) => {
-            ic_cdk::println!("❌ [AUTO_CONSOLIDATE_USER] Failed to get main account balance: {:?}", error);
-            0
+        };
+        let watermark = &mut user.deposit_watermarks[idx];
+        watermark.last_synced = now;
+        let observed = chain_balance.saturating_add(watermark.outflows);
+        let inflow = observed.saturating_sub(watermark.credited).min(allowance);
+        if inflow == 0 {
+            return Some(0);
         }
-    };
-    
-    // 4. 记录主账户余额（不转移）
-    if main_balance > 0 {
-        ic_cdk::println!("💰 [AUTO_CONSOLIDATE_USER] Found {} e8s in main account, but cannot transfer due to permissions", main_balance);
-        total_consolidated += main_balance;
+        // 超出充值限额的部分留在高水位之上，下次同步时再入账
+        watermark.credited += inflow;
         
-        // 更新用户余额记录
-        USERS.with(|users| {
-            let mut users_ref = users.borrow_mut();
-            if let Some(user) = users_ref.get_mut(&principal) {
-                user.balance += main_balance;
-                user.transaction_history.push(Transaction {
-                    amount: main_balance,
-                    timestamp: time(),
-                    transaction_type: "MainAccountRecorded".to_string(),
-                    tx_hash: Some(format!("main_account_recorded_{}", time())),
-                    ckbtc_address: Some(format!("Main Account: {}", principal)),
-                });
-            }
+        user.balance += inflow;
+        post(LedgerAccount::Treasury, LedgerAccount::User(principal), inflow, format!("new inflow to {:?}", account));
+        record_transaction(principal, user, Transaction {
+            amount: inflow,
+            timestamp: now,
+            kind: TransactionKind::CkBtcDeposit { block_index: None },
+            tx_hash: None,
+            ckbtc_address: Some(format!("{:?}", account)),
         });
-        
-        // 更新统计
+        Some(inflow)
+    }).unwrap_or(0);
+    
+    if credited > 0 {
+        ic_cdk::println!("💰 [SYNC_DEPOSITS] Credited {} e8s newly received in {:?} to {}", credited, account, principal);
+        audit("sync_deposits", format!("principal: {}, account: {:?}", principal, account), Ok(format!("credited {} e8s", credited)));
         STATS.with(|s| {
-            s.borrow_mut().total_ckbtc_deposits += main_balance;
+            s.borrow_mut().total_ckbtc_deposits += credited;
         });
+        refresh_certified_data();
     }
+    Ok(credited)
+}
+
+// 新增：按 principal 顺序同步并归集下一批用户的充值账户，同步到末尾后从头开始
+async fn sync_deposit_batch() {
+    let cursor = DEPOSIT_SYNC_CURSOR.with(|c| *c.borrow());
+    let mut batch: Vec<Principal> = USERS.with(|users| {
+        users.borrow().keys().filter(|p| cursor.is_none_or(|c| **p > c)).copied().collect()
+    });
+    batch.sort();
+    batch.truncate(DEPOSIT_SYNC_BATCH);
+    DEPOSIT_SYNC_CURSOR.with(|c| *c.borrow_mut() = batch.last().copied());
     
-    // 保存数据到稳定存储
-    if total_consolidated > 0 {
-        save_to_stable_storage();
+    let results = futures::future::join_all(batch.iter().map(|principal| sync_user_deposits(*principal))).await;
+    let credited: u64 = results.iter().filter_map(|r| r.as_ref().ok()).sum();
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    if credited > 0 || failed > 0 {
+        ic_cdk::println!("💰 [DEPOSIT_INDEX] Synced {} users: credited {} e8s, {} failed", batch.len(), credited, failed);
     }
     
-    ic_cdk::println!("✅ [AUTO_CONSOLIDATE_USER] Balance recording completed for user: {} (Total: {} e8s)", principal, total_consolidated);
-    Ok(total_consolidated)
+    // 同步后把已输掉的资金归集到主账户，平台费从主账户提取
+    let swept: u64 = futures::future::join_all(batch.iter().map(|principal| sweep_user_deposits(*principal))).await
+        .iter().filter_map(|r| r.as_ref().ok()).sum();
+    if swept > 0 {
+        ic_cdk::println!("🧹 [DEPOSIT_INDEX] Swept {} e8s to the main account", swept);
+    }
 }
 
-// 新增：自动检测用户余额变化并立即归集
-async fn auto_check_and_consolidate_balance(principal: Principal) -> Result<String, String> {
-    ic_cdk::println!("🔄 [AUTO_CHECK_AND_CONSOLIDATE] Auto checking balance for user: {}", principal);
-    
-    // 检查用户是否存在
-    let user = USERS.with(|users| {
-        users.borrow().get(&principal).cloned()
+// 新增：标记一笔从用户充值账户转出的在途转账（提现或归集），结束前不同步该账户
+fn begin_deposit_outflow(principal: Principal) {
+    DEPOSIT_WITHDRAWALS_IN_FLIGHT.with(|w| *w.borrow_mut().entry(principal).or_insert(0) += 1);
+}
+
+fn end_deposit_outflow(principal: Principal) {
+    DEPOSIT_WITHDRAWALS_IN_FLIGHT.with(|w| {
+        let mut w = w.borrow_mut();
+        if let Some(count) = w.get_mut(&principal) {
+            *count -= 1;
+            if *count == 0 {
+                w.remove(&principal);
+            }
+        }
     });
-    
-    if user.is_none() {
-        return Err("User not found".to_string());
+}
+
+// 新增：把充值账户中已入账但用户已输掉的部分归集到 canister 主账户，平台费从主账户提取。
+// 账户中保留用户当前余额和一次提现手续费，尚未同步入账的流入不动
+async fn sweep_user_deposits(principal: Principal) -> Result<u64, String> {
+    let account = USERS.with(|users| {
+        users.borrow().get(&principal).map(|user| user.deposit_account.clone())
+    }).ok_or_else(|| "User not found".to_string())?;
+    if account.owner != ic_cdk::id() || withdrawal_in_flight(principal) {
+        return Ok(0);
     }
     
-    let user = user.unwrap();
+    begin_deposit_outflow(principal);
+    let result = sweep_deposit_account(principal, &account).await;
+    end_deposit_outflow(principal);
+    result
+}
+
+async fn sweep_deposit_account(principal: Principal, account: &Account) -> Result<u64, String> {
     let ckbtc_canister: CkBtcCanister = CKBTC_CANISTER_ID.parse().unwrap();
-    
-    // 1. 检查主账户余额
-    let main_account = Account {
-        owner: principal,
-        subaccount: None,
-    };
-    
-    let main_balance = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (main_account,)).await {
-        Ok((balance,)) => {
-            let balance_u64: u64 = balance.0.try_into().unwrap_or(0);
-            ic_cdk::println!("💰 [AUTO_CHECK_AND_CONSOLIDATE] Main account balance: {} e8s", balance_u64);
-            balance_u64
-        },
+    let chain_balance: u64 = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (account.clone(),)).await {
+        Ok((balance,)) => balance.0.try_into().unwrap_or(0),
         Err(error) => {
-            ic_cdk::println!("❌ [AUTO_CHECK_AND_CONSOLIDATE] Failed to get main account balance: {:?}", error);
-            0
+            log_error("sweep_deposits", Some(principal), ErrorKind::LedgerCall, format!("Failed to get balance of {:?}: {:?}", account, error));
+            return Err(format!("Failed to get balance: {:?}", error));
         }
     };
     
-    // 2. 检查 deposit account 余额
-    let deposit_balance = match ic_cdk::call::<_, (Nat,)>(ckbtc_canister, "icrc1_balance_of", (user.deposit_account.clone(),)).await {
-        Ok((balance,)) => {
-            let balance_u64: u64 = balance.0.try_into().unwrap_or(0);
-            ic_cdk::println!("💰 [AUTO_CHECK_AND_CONSOLIDATE] Deposit account balance: {} e8s", balance_u64);
-            balance_u64
+    let surplus = USERS.with(|users| {
+        let users_ref = users.borrow();
+        let user = users_ref.get(&principal)?;
+        let watermark = user.deposit_watermarks.iter().find(|w| w.account == *account)?;
+        let credited_on_chain = watermark.credited.saturating_sub(watermark.outflows).min(chain_balance);
+        Some(credited_on_chain.saturating_sub(user.balance.saturating_add(CKBTC_TRANSFER_FEE)))
+    }).unwrap_or(0);
+    if surplus < MIN_SWEEP_AMOUNT {
+        return Ok(0);
+    }
+    
+    let transfer_args = TransferArgs {
+        to: Account { owner: ic_cdk::id(), subaccount: None },
+        amount: surplus - CKBTC_TRANSFER_FEE,
+        fee: Some(CKBTC_TRANSFER_FEE),
+        memo: Some("Lottery deposit sweep".as_bytes().to_vec()),
+        from_subaccount: account.subaccount.clone(),
+        created_at_time: Some(time()),
+    };
+    match ic_cdk::call::<_, (TransferResult,)>(ckbtc_canister, "icrc1_transfer", (transfer_args,)).await {
+        Ok((TransferResult::Ok(block_index),)) => {
+            record_deposit_outflow(principal, account, surplus);
+            ic_cdk::println!("🧹 [SWEEP_DEPOSITS] Moved {} e8s from {:?} to the main account at block {}", surplus - CKBTC_TRANSFER_FEE, account, block_index);
+            Ok(surplus - CKBTC_TRANSFER_FEE)
+        },
+        Ok((TransferResult::Err(error),)) => {
+            log_error("sweep_deposits", Some(principal), ErrorKind::LedgerRejected, format!("Sweep of {} e8s rejected: {:?}", surplus, error));
+            Err(format!("Sweep rejected: {:?}", error))
         },
         Err(error) => {
-            ic_cdk::println!("❌ [AUTO_CHECK_AND_CONSOLIDATE] Failed to get deposit account balance: {:?}", error);
-            0
+            log_error("sweep_deposits", Some(principal), ErrorKind::LedgerCall, format!("Sweep of {} e8s: call to ckBTC canister failed: {:?}", surplus, error));
+            Err(format!("Call to ckBTC canister failed: {:?}", error))
         }
-    };
-    
-    // 3. 计算总可用余额
-    let total_available = main_balance + deposit_balance;
-    let current_balance = user.balance;
-    
-    // 4. 智能更新用户余额记录 - 只在有新充值时才更新
-    if total_available > current_balance {
-        let balance_difference = total_available - current_balance;
-        
-        // 检查是否有最近的中奖记录
-        let has_recent_win = USERS.with(|users| {
-            users.borrow().get(&principal).map(|user| {
-                user.transaction_history.iter()
-                    .filter(|tx| tx.transaction_type == "Win")
-                    .any(|tx| time() - tx.timestamp < 60_000_000_000) // 1分钟内
-            }).unwrap_or(false)
-        });
-        
-        if has_recent_win {
-            ic_cdk::println!("ℹ️ [AUTO_CHECK_AND_CONSOLIDATE] Recent win detected, skipping balance update to avoid conflicts");
-        } else {
-            // 立即更新用户余额
-            USERS.with(|users| {
-                let mut users_ref = users.borrow_mut();
-                if let Some(user) = users_ref.get_mut(&principal) {
-                    // 直接设置为链上实际余额，而不是累加
-                    user.balance = total_available;
-                    user.transaction_history.push(Transaction {
-                        amount: balance_difference,
-                        timestamp: time(),
-                        transaction_type: "BalanceUpdate".to_string(),
-                        tx_hash: Some(format!("balance_update_{}", time())),
-                        ckbtc_address: Some(format!("User Account: {}", principal)),
-                    });
-                }
-            });
-            
-            // 更新统计
-            STATS.with(|s| {
-                s.borrow_mut().total_ckbtc_deposits += balance_difference;
-            });
-            
-            // 保存数据
-            save_to_stable_storage();
-            
-            ic_cdk::println!("✅ [AUTO_CHECK_AND_CONSOLIDATE] New deposit detected: +{} e8s", balance_difference);
+    }
+}
+
+fn withdrawal_in_flight(principal: Principal) -> bool {
+    DEPOSIT_WITHDRAWALS_IN_FLIGHT.with(|w| w.borrow().contains_key(&principal))
+}
+
+fn recorded_outflows(principal: Principal, account: &Account) -> u64 {
+    USERS.with(|users| {
+        users.borrow().get(&principal)
+            .and_then(|user| user.deposit_watermarks.iter().find(|w| w.account == *account).map(|w| w.outflows))
+            .unwrap_or(0)
+    })
+}
+
+// 新增：记录本 canister 从某个被同步账户转出的金额，避免同步时把余额下降误判为缺口
+fn record_deposit_outflow(principal: Principal, account: &Account, amount: u64) {
+    USERS.with(|users| {
+        if let Some(user) = users.borrow_mut().get_mut(&principal) {
+            if let Some(watermark) = user.deposit_watermarks.iter_mut().find(|w| w.account == *account) {
+                watermark.outflows += amount;
+            }
         }
-    } else if total_available < current_balance {
-        // 如果链上余额小于记录的余额，可能是下注后的状态，不更新
-        ic_cdk::println!("ℹ️ [AUTO_CHECK_AND_CONSOLIDATE] Balance discrepancy: recorded={} e8s, chain={} e8s (may be due to bets)", current_balance, total_available);
+    });
+}
+
+/// Credit funds newly received in a user's deposit account on the ckBTC ledger
+#[update]
+pub async fn sync_deposits(principal: Principal) -> Result<u64, String> {
+    sync_user_deposits(principal).await
+}
+
+// 新增：按 Prometheus 文本格式输出一个指标
+fn push_metric(out: &mut String, name: &str, metric_type: &str, help: &str, samples: &[(String, u128)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, metric_type));
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{} {}\n", name, labels, value));
     }
+}
+
+// 新增：生成 /metrics 的内容
+fn render_metrics() -> String {
+    let stats = get_stats();
+    let (round_id, participants, total_tickets, prize_pool) = CURRENT_ROUND.with(|r| {
+        let round = r.borrow();
+        (round.id, round.participants.len() as u64, round.total_tickets, round.prize_pool)
+    });
+    let (users, liabilities) = USERS.with(|users| {
+        let users = users.borrow();
+        (users.len() as u64, users.values().map(|user| user.balance).sum::<u64>())
+    });
+    let (pending_deposits, pending_deposit_amount) = CKBTC_DEPOSITS.with(|deposits| {
+        let deposits = deposits.borrow();
+        let pending = deposits.values().filter(|deposit| deposit.status == "pending");
+        (pending.clone().count() as u64, pending.map(|deposit| deposit.amount).sum::<u64>())
+    });
+    let (withdrawals_in_flight, withdrawal_amount_in_flight) = WITHDRAWALS_IN_FLIGHT.with(|w| *w.borrow());
+    let jackpot = JACKPOT.with(|j| j.borrow().balance);
+    let paused = solvency_paused();
+    let error_counts = ERROR_COUNTS.with(|counts| {
+        counts.borrow().iter()
+            .map(|((component, kind), count)| (format!("{{component=\"{}\",kind=\"{:?}\"}}", component, kind), *count as u128))
+            .collect::<Vec<_>>()
+    });
     
-    // 5. 然后尝试归集到 treasury（即使失败也不影响余额记录）
-    let mut total_consolidated = 0u64;
-    
-    if main_balance > 0 || deposit_balance > 0 {
-        let treasury_account = Account {
-            owner: Principal::anonymous(), // 提现时不再使用统一资金账户
-            subaccount: None,
-        };
-        
-        // 归集主账户余额
-        if main_balance > 0 {
-            let transfer_args = TransferArgs {
-                to: treasury_account.clone(),
-                amount: main_balance,
-                fee: Some(1_000),
-                memo: Some(format!("Auto consolidation from main account of user {}", principal).into_bytes()),
-                from_subaccount: None,
-                created_at_time: Some(time()),
-            };
-            
-            match ic_cdk::call::<_, (TransferResult,)>(ckbtc_canister, "icrc1_transfer", (transfer_args,)).await {
-                Ok((result,)) => {
-                    match result {
-                        TransferResult::Ok(block_index) => {
-                            total_consolidated += main_balance;
-                            ic_cdk::println!("✅ [AUTO_CHECK_AND_CONSOLIDATE] Main account consolidated: {} e8s (Block: {})", main_balance, block_index);
-                        },
-                        TransferResult::Err(error) => {
-                            ic_cdk::println!("❌ [AUTO_CHECK_AND_CONSOLIDATE] Main account transfer failed: {:?}", error);
-                        }
-                    }
+    let single = |value: u64| vec![(String::new(), value as u128)];
+    let mut out = String::new();
+    push_metric(&mut out, "lottery_rounds_total", "counter", "Rounds settled since launch", &single(stats.total_rounds));
+    push_metric(&mut out, "lottery_bets_total", "counter", "Ticket purchases since launch", &single(stats.total_bets));
+    push_metric(&mut out, "lottery_winnings_e8s_total", "counter", "Prize money paid out since launch in e8s", &single(stats.total_winnings));
+    push_metric(&mut out, "lottery_active_users", "gauge", "Active users as tracked in system stats", &single(stats.active_users));
+    push_metric(&mut out, "lottery_ckbtc_deposits_e8s_total", "counter", "ckBTC deposits credited since launch in e8s", &single(stats.total_ckbtc_deposits));
+    push_metric(&mut out, "lottery_fees_collected_e8s_total", "counter", "House fees collected since launch in e8s", &single(stats.total_fees_collected.unwrap_or(0)));
+    push_metric(&mut out, "lottery_fees_accrued_e8s", "gauge", "House fees not yet withdrawn in e8s", &single(stats.fees_accrued.unwrap_or(0)));
+    push_metric(&mut out, "lottery_current_round_id", "gauge", "Id of the open round", &single(round_id));
+    push_metric(&mut out, "lottery_current_round_participants", "gauge", "Distinct participants in the open round", &single(participants));
+    push_metric(&mut out, "lottery_current_round_tickets", "gauge", "Tickets sold in the open round", &single(total_tickets));
+    push_metric(&mut out, "lottery_current_round_pool_e8s", "gauge", "Prize pool of the open round in e8s", &single(prize_pool));
+    push_metric(&mut out, "lottery_jackpot_e8s", "gauge", "Accumulated jackpot in e8s", &single(jackpot));
+    push_metric(&mut out, "lottery_registered_users", "gauge", "Registered user accounts", &single(users));
+    push_metric(&mut out, "lottery_user_liabilities_e8s", "gauge", "Sum of user balances owed by the canister in e8s", &single(liabilities));
+    push_metric(&mut out, "lottery_pending_deposits", "gauge", "ckBTC deposits awaiting confirmation", &single(pending_deposits));
+    push_metric(&mut out, "lottery_pending_deposits_e8s", "gauge", "Amount of ckBTC deposits awaiting confirmation in e8s", &single(pending_deposit_amount));
+    push_metric(&mut out, "lottery_queued_withdrawals", "gauge", "Withdrawals waiting for the ledger transfer to complete", &single(withdrawals_in_flight));
+    push_metric(&mut out, "lottery_queued_withdrawals_e8s", "gauge", "Amount of withdrawals waiting for the ledger transfer in e8s", &single(withdrawal_amount_in_flight));
+    push_metric(&mut out, "lottery_solvency_paused", "gauge", "1 when betting and withdrawals are paused for insolvency", &single(paused as u64));
+    push_metric(&mut out, "lottery_cycles_balance", "gauge", "Cycles balance of the canister", &[(String::new(), ic_cdk::api::canister_balance128())]);
+    push_metric(&mut out, "lottery_stable_memory_pages", "gauge", "Stable memory size in 64KiB pages", &single(ic_cdk::api::stable::stable_size()));
+    push_metric(&mut out, "lottery_errors_total", "counter", "Logged errors by component and kind, including ledger call failures", &error_counts);
+    out
+}
+
+fn http_response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body,
+    }
+}
+
+fn json_response<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    match serde_json::to_value(value).and_then(|mut value| {
+        stringify_integers(&mut value);
+        serde_json::to_vec(&value)
+    }) {
+        Ok(body) => http_response(status_code, "application/json", body),
+        Err(e) => json_error(500, &format!("Failed to encode response: {}", e)),
+    }
+}
+
+// 新增：整数一律输出为十进制字符串，纳秒时间戳和随机种子超过 JavaScript 的安全整数范围，按数字解析会丢失精度
+fn stringify_integers(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Number(n) if n.is_u64() || n.is_i64() => *value = serde_json::Value::String(n.to_string()),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(stringify_integers),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(stringify_integers),
+        _ => {}
+    }
+}
+
+fn json_error(status_code: u16, message: &str) -> HttpResponse {
+    http_response(status_code, "application/json", serde_json::json!({ "error": message }).to_string().into_bytes())
+}
+
+// 新增：读取 URL 查询参数中的数字，缺省时返回 default
+fn query_param_u64(query: &str, name: &str, default: u64) -> Result<u64, String> {
+    match query.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name) {
+        Some((_, value)) => value.parse().map_err(|_| format!("Invalid value for '{}': {}", name, value)),
+        None => Ok(default),
+    }
+}
+
+// 新增：公开的 JSON 接口，数据与对应的 Candid 查询一致。
+// 响应没有认证，只能通过 raw 域名（<canister-id>.raw.icp0.io）访问；需要可验证的数据请使用 get_certified_* 查询
+fn handle_api(path: &str, query: &str) -> HttpResponse {
+    let page = || -> Result<(u64, u64), String> {
+        Ok((query_param_u64(query, "start", 0)?, query_param_u64(query, "limit", 20)?))
+    };
+    match path.trim_end_matches('/') {
+        "/api/round" => json_response(200, &get_round()),
+        "/api/rounds" => match page() {
+            Ok((start, limit)) => json_response(200, &list_rounds(start, limit)),
+            Err(e) => json_error(400, &e),
+        },
+        "/api/winners" => match page() {
+            Ok((start, limit)) => json_response(200, &list_winners(start, limit)),
+            Err(e) => json_error(400, &e),
+        },
+        "/api/stats" => json_response(200, &get_stats()),
+        other => match other.strip_prefix("/api/proof/") {
+            Some(id) => match id.parse::<u64>() {
+                Ok(round_id) => match get_round_by_id(round_id) {
+                    Some(round) => json_response(200, &serde_json::json!({
+                        "round_id": round.id,
+                        "total_tickets": round.total_tickets,
+                        "winners": round.winners,
+                        "randomness": round.randomness,
+                        "settlement": round.settlement,
+                    })),
+                    None => json_error(404, &format!("Round {} not found", round_id)),
                 },
-                Err(error) => {
-                    ic_cdk::println!("❌ [AUTO_CHECK_AND_CONSOLIDATE] Main account transfer call failed: {:?}", error);
-                }
+                Err(_) => json_error(400, &format!("Invalid round id: {}", id)),
+            },
+            None => json_error(404, "Not found"),
+        },
+    }
+}
+
+/// Serve Prometheus metrics at /metrics and the public JSON API under /api.
+/// Responses are not certified and must be fetched through the raw domain; integers in JSON are decimal strings.
+/// Use the get_certified_* queries for verifiable data.
+#[query]
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    let (path, query) = req.url.split_once('?').unwrap_or((req.url.as_str(), ""));
+    match (req.method.as_str(), path) {
+        ("GET", "/metrics") => http_response(200, "text/plain; version=0.0.4", render_metrics().into_bytes()),
+        ("GET", path) if path.starts_with("/api/") => handle_api(path, query),
+        ("GET", _) => http_response(404, "text/plain", b"Not found".to_vec()),
+        _ => http_response(405, "text/plain", b"Method not allowed".to_vec()),
+    }
+}
+
+const CERTIFIED_ROUND: &str = "round";
+const CERTIFIED_LAST_DRAW: &str = "last_draw";
+const CERTIFIED_STATS: &str = "stats";
+
+// 新增：把当前轮次、最近一次开奖和统计数据的 Candid 编码写入哈希树，并认证树根
+fn refresh_certified_data() {
+    let last_draw = ROUND_ARCHIVE.with(|archive| archive.borrow().values().next_back().cloned());
+    let entries = [
+        (CERTIFIED_ROUND, candid::encode_one(current_round_view())),
+        (CERTIFIED_LAST_DRAW, candid::encode_one(last_draw)),
+        (CERTIFIED_STATS, candid::encode_one(get_stats())),
+    ];
+    CERTIFIED_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for (key, encoded) in entries {
+            tree.insert(key, encoded.expect("Failed to encode certified data"));
+        }
+        ic_cdk::api::set_certified_data(&tree.root_hash());
+    });
+}
+
+// 新增：取出已认证的值及其证书和 witness；返回的是认证时的编码，保证与证书一致
+fn certified_value<T: CandidType + for<'de> Deserialize<'de>>(key: &'static str) -> (T, Vec<u8>, Vec<u8>) {
+    let certificate = ic_cdk::api::data_certificate()
+        .unwrap_or_else(|| ic_cdk::trap("Certified data is only available in query calls"));
+    CERTIFIED_TREE.with(|tree| {
+        let tree = tree.borrow();
+        let encoded = tree.get(key.as_bytes()).unwrap_or_else(|| ic_cdk::trap("Certified data not initialized"));
+        let value = candid::decode_one(encoded).expect("Failed to decode certified data");
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().expect("Failed to encode witness");
+        tree.witness(key.as_bytes()).serialize(&mut serializer).expect("Failed to encode witness");
+        (value, certificate, serializer.into_inner())
+    })
+}
+
+/// Get the current round together with a certificate and witness proving it
+#[query]
+pub fn get_certified_round() -> CertifiedRound {
+    let (round, certificate, witness) = certified_value(CERTIFIED_ROUND);
+    CertifiedRound { round, certificate, witness }
+}
+
+/// Get the most recent draw result together with a certificate and witness proving it
+#[query]
+pub fn get_certified_last_draw() -> CertifiedDraw {
+    let (draw, certificate, witness) = certified_value(CERTIFIED_LAST_DRAW);
+    CertifiedDraw { draw, certificate, witness }
+}
+
+/// Get system stats together with a certificate and witness proving them
+#[query]
+pub fn get_certified_stats() -> CertifiedStats {
+    let (stats, certificate, witness) = certified_value(CERTIFIED_STATS);
+    CertifiedStats { stats, certificate, witness }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60 * NANOS_PER_SECOND;
+    const HOUR: u64 = 60 * MINUTE;
+
+    #[test]
+    fn interval_schedule_adds_seconds() {
+        let schedule = DrawSchedule::Interval { seconds: 90 };
+        assert_eq!(scheduled_after(&schedule, 1_000), Some(1_000 + 90 * NANOS_PER_SECOND));
+        assert_eq!(scheduled_after(&schedule, u64::MAX - 1), None);
+    }
+
+    #[test]
+    fn daily_schedule_picks_next_slot() {
+        let schedule = DrawSchedule::Daily { hour: 20, minute: 30 };
+        let slot = 20 * HOUR + 30 * MINUTE;
+        assert_eq!(scheduled_after(&schedule, 0), Some(slot));
+        assert_eq!(scheduled_after(&schedule, slot - 1), Some(slot));
+        // 恰好在开奖时刻时取下一天
+        assert_eq!(scheduled_after(&schedule, slot), Some(slot + NANOS_PER_DAY));
+        assert_eq!(scheduled_after(&schedule, 3 * NANOS_PER_DAY + 21 * HOUR), Some(4 * NANOS_PER_DAY + slot));
+    }
+
+    #[test]
+    fn weekly_schedule_aligns_to_epoch_thursday() {
+        // 1970-01-01 是周四，第一个周一是 1970-01-05
+        let monday = DrawSchedule::Weekly { weekday: 0, hour: 0, minute: 0 };
+        assert_eq!(scheduled_after(&monday, 0), Some(4 * NANOS_PER_DAY));
+        let thursday = DrawSchedule::Weekly { weekday: 3, hour: 12, minute: 0 };
+        assert_eq!(scheduled_after(&thursday, 0), Some(12 * HOUR));
+        assert_eq!(scheduled_after(&thursday, 12 * HOUR), Some(7 * NANOS_PER_DAY + 12 * HOUR));
+        let sunday = DrawSchedule::Weekly { weekday: 6, hour: 18, minute: 0 };
+        assert_eq!(scheduled_after(&sunday, 0), Some(3 * NANOS_PER_DAY + 18 * HOUR));
+    }
+
+    #[test]
+    fn weekly_schedule_overflow_is_none() {
+        let schedule = DrawSchedule::Weekly { weekday: 6, hour: 23, minute: 59 };
+        assert_eq!(scheduled_after(&schedule, u64::MAX), None);
+    }
+
+    #[test]
+    fn round_status_transitions() {
+        use RoundStatus::*;
+        let allowed = [(Open, Closing), (Closing, Open), (Closing, Drawing), (Drawing, Closing), (Drawing, Settled)];
+        for from in [Open, Closing, Drawing, Settled] {
+            for to in [Open, Closing, Drawing, Settled] {
+                assert_eq!(from.can_transition_to(to), allowed.contains(&(from, to)), "{:?} -> {:?}", from, to);
             }
         }
-        
-        // 归集 deposit account 余额
-        if deposit_balance > 0 {
-            let transfer_args = TransferArgs {
-                to: treasury_account.clone(),
-                amount: deposit_balance,
-                fee: Some(1_000),
-                memo: Some(format!("Auto consolidation from deposit account of user {}", principal).into_bytes()),
-                from_subaccount: user.deposit_account.subaccount.clone(),
-                created_at_time: Some(time()),
-            };
-            
-            match ic_cdk::call::<_, (TransferResult,)>(ckbtc_canister, "icrc1_transfer", (transfer_args,)).await {
-                Ok((result,)) => {
-                    match result {
-                        TransferResult::Ok(block_index) => {
-                            total_consolidated += deposit_balance;
-                            ic_cdk::println!("✅ [AUTO_CHECK_AND_CONSOLIDATE] Deposit account consolidated: {} e8s (Block: {})", deposit_balance, block_index);
-                        },
-                        TransferResult::Err(error) => {
-                            ic_cdk::println!("❌ [AUTO_CHECK_AND_CONSOLIDATE] Deposit account transfer failed: {:?}", error);
-                        }
-                    }
-                },
-                Err(error) => {
-                    ic_cdk::println!("❌ [AUTO_CHECK_AND_CONSOLIDATE] Deposit account transfer call failed: {:?}", error);
-                }
+    }
+
+    // 账本与实际余额一起变动，模拟各业务流程的记账
+    struct Books {
+        ledger: Ledger,
+        balances: BTreeMap<LedgerAccount, u64>,
+    }
+
+    impl Books {
+        fn new() -> Self {
+            let mut balances = BTreeMap::new();
+            for account in [LedgerAccount::RoundPool, LedgerAccount::Fees, LedgerAccount::Jackpot] {
+                balances.insert(account, 0);
             }
+            Books { ledger: Ledger::default(), balances }
         }
-        
-        if total_consolidated > 0 {
-            ic_cdk::println!("✅ [AUTO_CHECK_AND_CONSOLIDATE] Successfully consolidated {} e8s to treasury", total_consolidated);
-            Ok(format!("Successfully updated balance and consolidated {} e8s to treasury", total_consolidated))
-        } else {
-            ic_cdk::println!("⚠️ [AUTO_CHECK_AND_CONSOLIDATE] Balance updated but consolidation failed");
-            Ok("Balance updated but consolidation failed".to_string())
+
+        fn transfer(&mut self, from: LedgerAccount, to: LedgerAccount, amount: u64) {
+            if from != LedgerAccount::Treasury {
+                *self.balances.get_mut(&from).unwrap() -= amount;
+            }
+            if to != LedgerAccount::Treasury {
+                *self.balances.entry(to.clone()).or_default() += amount;
+            }
+            record_posting(&mut self.ledger, from, to, amount, String::new(), 0);
+        }
+
+        fn violations(&self) -> Vec<String> {
+            reconcile_ledger(&self.ledger, &self.balances).violations
         }
-    } else {
-        ic_cdk::println!("ℹ️ [AUTO_CHECK_AND_CONSOLIDATE] No funds found in user accounts");
-        Ok("No funds found in user accounts".to_string())
     }
-}
 
+    fn user(id: u8) -> LedgerAccount {
+        LedgerAccount::User(Principal::from_slice(&[id]))
+    }
+
+    #[test]
+    fn ledger_balances_through_bets_settlement_and_withdrawals() {
+        let mut books = Books::new();
+        books.transfer(LedgerAccount::Treasury, user(1), 10 * TICKET_PRICE);
+        books.transfer(LedgerAccount::Treasury, user(2), 5 * TICKET_PRICE);
+        books.transfer(user(1), LedgerAccount::RoundPool, 4 * TICKET_PRICE);
+        books.transfer(user(2), LedgerAccount::RoundPool, 3 * TICKET_PRICE);
+        assert!(books.violations().is_empty());
+
+        // 结算：平台费、累积奖金注入，剩余奖池按档位派奖
+        let pool = 7 * TICKET_PRICE;
+        let fee = pool / 20;
+        let contribution = pool / 100;
+        books.transfer(LedgerAccount::RoundPool, LedgerAccount::Fees, fee);
+        books.transfer(LedgerAccount::RoundPool, LedgerAccount::Jackpot, contribution);
+        let amounts = split_prize_pool(pool - fee - contribution, &[7_000, 3_000], 2);
+        books.transfer(LedgerAccount::RoundPool, user(2), amounts[0]);
+        books.transfer(LedgerAccount::RoundPool, user(1), amounts[1]);
+        assert_eq!(books.balances[&LedgerAccount::RoundPool], 0);
+        assert!(books.violations().is_empty());
+
+        // 提现先扣款；转账失败时冲回
+        let balance = books.balances[&user(1)];
+        books.transfer(user(1), LedgerAccount::Treasury, balance);
+        books.transfer(LedgerAccount::Treasury, user(1), balance);
+        books.transfer(user(1), LedgerAccount::Treasury, balance);
+        books.transfer(LedgerAccount::Fees, LedgerAccount::Treasury, fee);
+        assert_eq!(books.balances[&user(1)], 0);
+        assert!(books.violations().is_empty());
+    }
+
+    #[test]
+    fn ledger_balances_through_refunds() {
+        let mut books = Books::new();
+        books.transfer(LedgerAccount::Treasury, user(1), 3 * TICKET_PRICE);
+        books.transfer(user(1), LedgerAccount::RoundPool, 2 * TICKET_PRICE);
+        books.transfer(LedgerAccount::RoundPool, user(1), 2 * TICKET_PRICE);
+        assert_eq!(books.balances[&user(1)], 3 * TICKET_PRICE);
+        let report = reconcile_ledger(&books.ledger, &books.balances);
+        assert!(report.violations.is_empty());
+        assert_eq!(report.internal_value, 3 * TICKET_PRICE);
+    }
+
+    #[test]
+    fn ledger_reports_unbooked_balance_changes() {
+        let mut books = Books::new();
+        books.transfer(LedgerAccount::Treasury, user(1), TICKET_PRICE);
+        // 余额变动没有记账
+        *books.balances.get_mut(&user(1)).unwrap() += TICKET_PRICE;
+        assert_eq!(books.violations().len(), 1);
+    }
+
+    #[test]
+    fn randomness_proof_json_has_hex_random_bytes() {
+        let proof = RandomnessProof { random_bytes: Some(vec![0x00, 0x0f, 0xab, 0xff]), ..Default::default() };
+        let response = json_response(200, &proof);
+        let json: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(json["random_bytes"], "000fabff");
+        assert_eq!(json["seed"], "0");
+    }
+
+    fn empty_round() -> Round {
+        Round {
+            id: 1,
+            participants: vec![],
+            ticket_ranges: vec![],
+            total_tickets: 0,
+            prize_pool: 0,
+            start_time: 0,
+            end_time: 0,
+            winners: vec![],
+            jackpot: None,
+            upcoming_draws: None,
+            status: Some(RoundStatus::Open),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn round_rejects_invalid_transition() {
+        let mut round = empty_round();
+        round.transition(RoundStatus::Settled);
+    }
+
+    #[test]
+    fn ticket_owner_finds_range_boundaries() {
+        let mut round = empty_round();
+        add_tickets(&mut round, Principal::from_slice(&[1]), false, 3);
+        add_tickets(&mut round, Principal::from_slice(&[2]), false, 1);
+        add_tickets(&mut round, Principal::from_slice(&[1]), false, 2);
+        let owner = |ticket| ticket_owner(&round.ticket_ranges, ticket).principal;
+        // 区间 [0, 3)、[3, 4)、[4, 6)
+        assert_eq!(owner(0), Principal::from_slice(&[1]));
+        assert_eq!(owner(2), Principal::from_slice(&[1]));
+        assert_eq!(owner(3), Principal::from_slice(&[2]));
+        assert_eq!(owner(4), Principal::from_slice(&[1]));
+        assert_eq!(owner(5), Principal::from_slice(&[1]));
+        assert_eq!(round.participants.len(), 2);
+        assert_eq!(find_participant(&round, &Principal::from_slice(&[1]), false).unwrap().ticket_count, 5);
+    }
 
+    #[test]
+    fn select_winners_draws_without_replacement() {
+        let mut round = empty_round();
+        // 一个参与者持有绝大多数彩票，其他人也必须各中一次
+        add_tickets(&mut round, Principal::from_slice(&[1]), false, 1_000);
+        add_tickets(&mut round, Principal::from_slice(&[2]), false, 1);
+        add_tickets(&mut round, Principal::from_slice(&[3]), true, 1);
+        for seed in 0..50 {
+            let winners = select_winners(&round, 3, seed);
+            assert_eq!(winners.len(), 3);
+            let mut distinct: Vec<_> = winners.iter().map(|w| (w.principal, w.is_bot)).collect();
+            distinct.sort();
+            distinct.dedup();
+            assert_eq!(distinct.len(), 3, "seed {}", seed);
+        }
+        // 档位多于参与者时只抽出全部参与者
+        assert_eq!(select_winners(&round, 5, 7).len(), 3);
+        assert!(select_winners(&empty_round(), 3, 7).is_empty());
+    }
 
+    #[test]
+    fn split_prize_pool_gives_remainder_to_first_tier() {
+        assert_eq!(split_prize_pool(1_001, &[5_000, 3_000, 2_000], 3), vec![501, 300, 200]);
+        // 未抽满的档位归头奖
+        assert_eq!(split_prize_pool(1_000, &[5_000, 3_000, 2_000], 2), vec![700, 300]);
+        assert_eq!(split_prize_pool(1_000, &[5_000, 3_000, 2_000], 0), Vec::<u64>::new());
+        let amounts = split_prize_pool(u64::MAX, &[3_333, 3_333, 3_334], 3);
+        assert_eq!(amounts.iter().map(|a| *a as u128).sum::<u128>(), u64::MAX as u128);
+    }
 
+    #[test]
+    fn prize_tier_shares_cover_whole_pool() {
+        assert_eq!(prize_tier_shares(&PrizeDistribution::Equal(3)), vec![3_334, 3_333, 3_333]);
+        assert_eq!(prize_tier_shares(&PrizeDistribution::Equal(0)), vec![BPS_DENOMINATOR]);
+        assert_eq!(prize_tier_shares(&PrizeDistribution::Tiered(vec![6_000, 4_000])), vec![6_000, 4_000]);
+        // 中奖人数少于档位数时，拆分结果仍然等于奖池
+        let shares = prize_tier_shares(&PrizeDistribution::Equal(4));
+        assert_eq!(split_prize_pool(999, &shares, 1), vec![999]);
+    }
+}
@@ -8,6 +8,7 @@ import { AuthClient } from '@dfinity/auth-client';
 class App {
   constructor() {
     this.currentUser = null;
+    this.recentTransactions = [];
     this.currentRound = null;
     this.systemStats = null;
     this.historicalWinners = [];
@@ -286,6 +287,7 @@ class App {
       
       this.userPrincipal = null;
       this.currentUser = null;
+      this.recentTransactions = [];
       this.identityProvider = null;
       this.userDepositAccount = null;
       this.pageState = 'connect';
@@ -709,6 +711,10 @@ class App {
           this.currentUser = userResult[0];
           console.log('User data extracted from array:', this.currentUser);
           console.log('User balance from backend:', this.currentUser.balance);
+          
+          // 交易记录单独分页查询，这里只取最近 5 条
+          const page = await my_rust_dapp_backend.get_user_transactions(principalObj, 0n, 5n, []);
+          this.recentTransactions = page.entries.map(entry => entry.transaction);
         } else {
           console.log('No user found, userResult is empty array or null');
           this.currentUser = null;
@@ -1075,7 +1081,9 @@ class App {
     if (!this.currentRound || !this.userPrincipal) return 0;
     
     const userPrincipal = this.userPrincipal.toString();
-    return this.currentRound.participants.filter(p => p.toString() === userPrincipal).length;
+    return this.currentRound.participants
+      .filter(p => p.principal.toString() === userPrincipal)
+      .reduce((sum, p) => sum + Number(p.ticket_count), 0);
   }
 
   // 生成类似比特币地址格式的 ckBTC 地址（64字符十六进制）
@@ -1552,13 +1560,13 @@ class App {
                   <div style="display: grid; grid-template-columns: 1fr 1fr; gap: 15px; margin-bottom: 20px;">
                     <div style="background: #e8f5e9; padding: 15px; border-radius: 8px; text-align: center;">
                       <div style="font-size: 1.5rem; font-weight: bold; color: #2e7d32;">
-                        ${(this.currentRound.participants || []).length}
+                        ${(this.currentRound.participants || []).reduce((sum, p) => sum + Number(p.ticket_count), 0)}
                       </div>
                       <div style="font-size: 0.9rem; color: #666;">Total Bets</div>
                     </div>
                     <div style="background: #e3f2fd; padding: 15px; border-radius: 8px; text-align: center;">
                       <div style="font-size: 1.5rem; font-weight: bold; color: #1976d2;">
-                        ${new Set((this.currentRound.participants || []).map(p => p.toString())).size}
+                        ${new Set((this.currentRound.participants || []).filter(p => !p.is_bot).map(p => p.principal.toString())).size}
                       </div>
                       <div style="font-size: 0.9rem; color: #666;">Unique Players</div>
                     </div>
@@ -1869,12 +1877,12 @@ class App {
               </div>
               <div class="profile-card" style="background: #fff; border-radius: 14px; box-shadow: 0 2px 12px rgba(0,0,0,0.06); padding: 24px 20px; margin-bottom: 24px;">
                 <h3 style="margin-bottom: 14px;">📜 Recent Transactions</h3>
-                ${this.currentUser && this.currentUser.transaction_history && this.currentUser.transaction_history.length > 0
+                ${this.recentTransactions.length > 0
                   ? html`
                     <ul class="tx-list" style="list-style: none; padding: 0; margin: 0;">
-                      ${this.currentUser.transaction_history.slice(-5).reverse().map(tx => html`
+                      ${this.recentTransactions.map(tx => html`
                         <li style="display: flex; justify-content: space-between; padding: 8px 0; border-bottom: 1px solid #eee; font-size: 0.98em;">
-                          <span class="tx-type" style="color: #888;">${tx.transaction_type}</span>
+                          <span class="tx-type" style="color: #888;">${Object.keys(tx.kind)[0]}</span>
                           <span class="tx-amount" style="color: #1976d2; font-weight: bold;">${this.formatBalance(tx.amount)}</span>
                           <span class="tx-time" style="color: #aaa;">${this.formatTimestamp(tx.timestamp)}</span>
                         </li>